@@ -3,6 +3,8 @@ use byteorder::{ByteOrder, BE};
 use serde::{Deserialize, Serialize};
 use std::fmt;
 
+use crate::reltag::RelTag;
+
 /// Key used in the Repository kv-store.
 ///
 /// The Repository treats this as an opaque struct, but see the code in pgdatadir_mapping.rs
@@ -77,6 +79,12 @@ impl Key {
         key
     }
 
+    /// Whether this key holds a block of relation data, as opposed to relation sizes,
+    /// directories, SLRUs or other metadata.  See the keyspace layout in pgdatadir_mapping.rs.
+    pub fn is_rel_block_key(&self) -> bool {
+        self.field1 == 0x00 && self.field4 != 0 && self.field6 != 0xffffffff
+    }
+
     pub fn from_slice(b: &[u8]) -> Self {
         Key {
             field1: b[0],
@@ -98,6 +106,18 @@ impl Key {
     }
 }
 
+/// The Key holding block `blknum` of relation `rel`
+pub fn rel_block_to_key(rel: RelTag, blknum: u32) -> Key {
+    Key {
+        field1: 0x00,
+        field2: rel.spcnode,
+        field3: rel.dbnode,
+        field4: rel.relnode,
+        field5: rel.forknum,
+        field6: blknum,
+    }
+}
+
 impl fmt::Display for Key {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
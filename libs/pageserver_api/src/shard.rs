@@ -1,5 +1,23 @@
-use std::{ops::RangeInclusive, str::FromStr};
+//! Sharding types: which shard of a tenant owns a key, and how shards are named.
+//!
+//! [`ShardNumber`] and [`ShardCount`] are a byte each, and so are the [`ShardIndex`] and
+//! [`TenantShardId`] they make up, whose binary encodings are fixed size. Shard counts above
+//! 255 can only be represented by [`WideShardIndex`]: the `ShardIndex` and `TenantShardId`
+//! parsers accept the two-byte [`LAYOUT_V2`] string form, but reject values that do not fit
+//! in a byte with a `TooWide` error.
 
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    num::{NonZeroU32, NonZeroU64},
+    ops::{Range, RangeInclusive},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::{
+    key::{rel_block_to_key, Key},
+    reltag::RelTag,
+};
 use hex::FromHex;
 use serde::{Deserialize, Serialize};
 use thiserror;
@@ -13,10 +31,88 @@ pub struct ShardCount(pub u8);
 
 impl ShardCount {
     pub const MAX: Self = Self(u8::MAX);
+
+    pub const fn count(&self) -> u8 {
+        self.0
+    }
+
+    /// The numbers of all the shards in this count.  A legacy unsharded tenant (count 0)
+    /// still has one shard, so yields `ShardNumber(0)`.
+    pub fn shard_numbers(&self) -> impl DoubleEndedIterator<Item = ShardNumber> {
+        (0..self.0.max(1)).map(ShardNumber)
+    }
+
+    /// Whether this is the count of a legacy tenant from before sharding.  Such tenants
+    /// have a single shard, but unlike a modern single-shard tenant (count 1) their ids have
+    /// no shard suffix.
+    pub fn is_unsharded(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Whether a tenant with `parent` shards may be split into this many: this count must be
+    /// a larger multiple of `parent`.  Both must be nonzero, so legacy counts never qualify.
+    pub fn is_split_of(&self, parent: ShardCount) -> bool {
+        parent.0 != 0 && self.0 > parent.0 && self.0 % parent.0 == 0
+    }
+
+    /// Whether this is a power-of-two count.  False for the legacy count 0.
+    pub fn is_power_of_two(&self) -> bool {
+        self.0.is_power_of_two()
+    }
+
+    /// Whether splitting a tenant with this count into `target` shards would leave its
+    /// layout unchanged.  Legacy unsharded tenants (count 0) already have a single shard,
+    /// so splitting them to a count of 1 is also a no-op.
+    pub fn is_noop_split(&self, target: ShardCount) -> bool {
+        self.0.max(1) == target.0.max(1)
+    }
+
+    /// All the shard numbers in this count, rotated to start at `seed % count`.  Seeding this
+    /// per tenant spreads background work across shards rather than always starting at
+    /// shard zero.
+    pub fn rotated_order(&self, seed: u64) -> Vec<ShardNumber> {
+        // A legacy unsharded tenant has a single shard, numbered zero
+        let count = self.0.max(1);
+        let start = (seed % count as u64) as u8;
+        (start..count).chain(0..start).map(ShardNumber).collect()
+    }
+
+    /// The order in which to drain this tenant's shards from a node being decommissioned:
+    /// highest shard number first.
+    pub fn drain_order(&self) -> Vec<ShardNumber> {
+        self.shard_numbers().rev().collect()
+    }
+
+    /// Like [`Self::drain_order`], but rotated by `seed`, so that draining many tenants does
+    /// not move all their highest-numbered shards at once.
+    pub fn drain_order_seeded(&self, seed: u64) -> Vec<ShardNumber> {
+        let mut order = self.drain_order();
+        let len = order.len() as u64;
+        order.rotate_left((seed % len) as usize);
+        order
+    }
+
+    /// The number of index_part.json objects we expect in remote storage for a tenant with
+    /// this many shards and `timelines` timelines: one per timeline per shard.  Legacy
+    /// unsharded tenants have a single shard.
+    pub fn expected_index_parts(&self, timelines: usize) -> usize {
+        self.0.max(1) as usize * timelines
+    }
+
+    /// The number of decimal digits needed to print any shard number in this count, for
+    /// aligning shard numbers in tabular output.
+    pub fn max_decimal_width(&self) -> usize {
+        self.0.saturating_sub(1).to_string().len()
+    }
 }
 
 impl ShardNumber {
     pub const MAX: Self = Self(u8::MAX);
+
+    /// Decimal representation, zero-padded to `width` (e.g. `007`)
+    pub fn pad_decimal(&self, width: usize) -> String {
+        format!("{:0width$}", self.0)
+    }
 }
 
 /// TenantShardId identify the units of work for the Pageserver.
@@ -39,13 +135,64 @@ impl ShardNumber {
 /// Note that the binary encoding is _not_ backward compatible, because
 /// at the time sharding is introduced, there are no existing binary structures
 /// containing TenantId that we need to handle.
-#[derive(Eq, PartialEq, PartialOrd, Ord, Clone, Copy, Hash)]
+#[derive(Eq, PartialEq, Clone, Copy, Hash)]
 pub struct TenantShardId {
     pub tenant_id: TenantId,
     pub shard_number: ShardNumber,
     pub shard_count: ShardCount,
 }
 
+/// An explicitly tagged alternative to TenantShardId's string form, for JSON APIs that should
+/// not rely on the legacy `shard_count == 0` convention, e.g.
+/// `{"mode":"sharded","id":"1f359dd625e519a1a4e8d7509690f6fc-0104"}`.
+#[derive(Clone, Copy, Serialize, Deserialize, Eq, PartialEq, Debug)]
+#[serde(tag = "mode", content = "id", rename_all = "snake_case")]
+pub enum TenantShardRef {
+    /// A tenant from before sharding, with no shard count
+    Legacy(TenantId),
+    Sharded(TenantShardId),
+}
+
+impl From<TenantShardId> for TenantShardRef {
+    fn from(id: TenantShardId) -> Self {
+        if id == TenantShardId::unsharded(id.tenant_id) {
+            Self::Legacy(id.tenant_id)
+        } else {
+            Self::Sharded(id)
+        }
+    }
+}
+
+impl From<TenantShardRef> for TenantShardId {
+    fn from(r: TenantShardRef) -> Self {
+        match r {
+            TenantShardRef::Legacy(tenant_id) => Self::unsharded(tenant_id),
+            TenantShardRef::Sharded(id) => id,
+        }
+    }
+}
+
+/// Selects a subset of a tenant's shards, for bulk operations.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ShardSelector {
+    /// Every shard in the tenant
+    All,
+    /// Exactly one shard
+    Single(ShardNumber),
+    /// An inclusive range of shard numbers
+    Range(ShardNumber, ShardNumber),
+}
+
+impl ShardSelector {
+    fn matches(&self, number: ShardNumber) -> bool {
+        match self {
+            Self::All => true,
+            Self::Single(n) => number == *n,
+            Self::Range(start, end) => number >= *start && number <= *end,
+        }
+    }
+}
+
 impl TenantShardId {
     pub fn unsharded(tenant_id: TenantId) -> Self {
         Self {
@@ -57,6 +204,9 @@ impl TenantShardId {
 
     /// The range of all TenantShardId that belong to a particular TenantId.  This is useful when
     /// you have a BTreeMap of TenantShardId, and are querying by TenantId.
+    ///
+    /// This relies on the ordering of TenantShardId sorting by tenant_id first, so that all
+    /// of a tenant's shards form a contiguous block, whatever their shard counts.
     pub fn tenant_range(tenant_id: TenantId) -> RangeInclusive<Self> {
         RangeInclusive::new(
             Self {
@@ -72,14 +222,461 @@ impl TenantShardId {
         )
     }
 
+    /// Expand `selector` into the ids of the matching shards of a tenant with `count` shards.
+    /// Shard numbers that do not exist at this count are ignored.
+    pub fn select(tenant_id: TenantId, count: ShardCount, selector: ShardSelector) -> Vec<Self> {
+        count
+            .shard_numbers()
+            .filter(|number| selector.matches(*number))
+            .map(|shard_number| Self {
+                tenant_id,
+                shard_number,
+                shard_count: count,
+            })
+            .collect()
+    }
+
+    /// The ids of the shards that replace this one when its tenant is split into `new_count`
+    /// shards.  Between them, the children own exactly the keys this shard owned: child `n`
+    /// of the new count descends from parent `n % old_count`.
+    pub fn split(&self, new_count: ShardCount) -> Result<Vec<Self>, ShardSplitError> {
+        Ok(
+            split_shard_numbers(self.shard_number, self.shard_count, new_count)?
+                .map(|shard_number| Self {
+                    tenant_id: self.tenant_id,
+                    shard_number,
+                    shard_count: new_count,
+                })
+                .collect(),
+        )
+    }
+
+    /// Check the compatibility contract described on [`TenantShardId`] for `tenant_id`: the
+    /// string form of its unsharded TenantShardId parses back as the same TenantId, and its
+    /// TenantId string parses as that unsharded TenantShardId.
+    pub fn verify_legacy_compat(tenant_id: TenantId) -> bool {
+        let unsharded = Self::unsharded(tenant_id);
+        let forward = TenantId::from_str(&unsharded.to_string()).map_or(false, |t| t == tenant_id);
+        let backward = Self::from_str(&tenant_id.to_string()).map_or(false, |t| t == unsharded);
+        forward && backward
+    }
+
+    /// Like [`FromStr`], but requires the shard suffix, rejecting bare tenant ids.  For callers
+    /// that must not confuse a legacy unsharded tenant with a single-shard one.
+    pub fn from_str_strict(s: &str) -> Result<Self, TenantShardIdParseError> {
+        if s.len() == 32 {
+            return Err(TenantShardIdParseError::MissingSuffix);
+        }
+        Self::from_str(s)
+    }
+
+    /// Parse an id followed by a generation suffix, as written in some metadata: e.g.
+    /// `<tenant_id>-0104-00000005` for generation 5 of a sharded tenant, or
+    /// `<tenant_id>-00000005` for a legacy one.
+    pub fn from_generational_str(s: &str) -> Result<(Self, u32), TenantShardIdParseError> {
+        let (id, generation) = s
+            .rsplit_once('-')
+            .ok_or(TenantShardIdParseError::MissingSeparator)?;
+        if generation.len() != 8 || !generation.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(TenantShardIdParseError::BadGeneration);
+        }
+        let generation = u32::from_str_radix(generation, 16)
+            .map_err(|_| TenantShardIdParseError::BadGeneration)?;
+        Ok((Self::from_str(id)?, generation))
+    }
+
+    /// Like [`FromStr`], but also rejects ids whose shard number is not below their shard
+    /// count, as [`ShardIdentity::new`] would.  The legacy `0000` suffix is allowed.
+    pub fn from_str_validated(s: &str) -> Result<Self, TenantShardIdParseError> {
+        let id = Self::from_str(s)?;
+        match ShardIndex::try_from_tenant_shard_id(&id) {
+            Ok(_) => Ok(id),
+            Err(_) => Err(TenantShardIdParseError::InvalidShard {
+                number: id.shard_number,
+                count: id.shard_count,
+            }),
+        }
+    }
+
+    /// Like [`FromStr`], but on failure reports every problem found rather than just the
+    /// first, as human-readable messages for operators.
+    pub fn parse_diagnostic(s: &str) -> Result<Self, Vec<String>> {
+        let mut issues = Vec::new();
+
+        let sharded = s.len() > 32;
+        if !matches!(s.len(), 32 | 37 | 41) {
+            issues.push(format!("expected 32, 37 or 41 characters, got {}", s.len()));
+        }
+        for (i, c) in s.char_indices() {
+            if sharded && i == 32 {
+                if c != '-' {
+                    issues.push(format!("expected '-' at position 32, found {c:?}"));
+                }
+            } else if !c.is_ascii_hexdigit() {
+                issues.push(format!("invalid hex character {c:?} at position {i}"));
+            }
+        }
+
+        if s.len() == 37 {
+            let mut shard_parts = [0u8; 2];
+            if hex::decode_to_slice(&s.as_bytes()[33..37], &mut shard_parts).is_ok()
+                && shard_parts[0] >= shard_parts[1].max(1)
+            {
+                issues.push(format!(
+                    "shard number {} is not less than shard count {}",
+                    shard_parts[0], shard_parts[1]
+                ));
+            }
+        }
+
+        if !issues.is_empty() {
+            return Err(issues);
+        }
+        Self::from_str(s).map_err(|e| vec![e.to_string()])
+    }
+
+    /// Inverse of [`Self::split`]: the shard of a tenant with `old_count` shards whose keys
+    /// this shard owns a part of.
+    pub fn parent(&self, old_count: ShardCount) -> Result<TenantShardId, ShardMergeError> {
+        // A legacy unsharded tenant has a single shard
+        let count = self.shard_count.0.max(1);
+        let old = old_count.0.max(1);
+        if old >= count {
+            return Err(ShardMergeError::NotSmaller {
+                count: self.shard_count,
+                old: old_count,
+            });
+        }
+        if count % old != 0 {
+            return Err(ShardMergeError::NotDivisor {
+                count: self.shard_count,
+                old: old_count,
+            });
+        }
+
+        Ok(Self {
+            tenant_id: self.tenant_id,
+            shard_number: ShardNumber(self.shard_number.0 % old),
+            shard_count: old_count,
+        })
+    }
+
+    /// This shard's index within its tenant, without the tenant id
+    pub fn to_index(&self) -> ShardIndex {
+        ShardIndex::from(self)
+    }
+
+    /// A short, human-friendly code for this shard, for use in tickets and conversation: the
+    /// last 6 hex digits of the tenant id and the shard suffix, e.g. `90f6fc-070a`.  Codes are
+    /// not unique: use [`Self::resolve_short_code`] to look them up among known ids.
+    pub fn short_code(&self) -> String {
+        let tenant = self.tenant_id.to_string();
+        format!(
+            "{}{}",
+            &tenant[tenant.len() - 6..],
+            self.to_index().get_suffix()
+        )
+    }
+
+    /// The id among `candidates` whose [`Self::short_code`] is `short`, ignoring case.  None if
+    /// there is no such id, or more than one.
+    pub fn resolve_short_code(short: &str, candidates: &[TenantShardId]) -> Option<TenantShardId> {
+        let mut matches = candidates
+            .iter()
+            .filter(|id| id.short_code().eq_ignore_ascii_case(short));
+        match (matches.next(), matches.next()) {
+            (Some(id), None) => Some(*id),
+            _ => None,
+        }
+    }
+
     pub fn shard_slug(&self) -> String {
-        format!("{:02x}{:02x}", self.shard_number.0, self.shard_count.0)
+        let mut slug = String::with_capacity(4);
+        self.write_shard_slug(&mut slug)
+            .expect("writing to a String cannot fail");
+        slug
+    }
+
+    /// Like [`Self::shard_slug`], but writes into `w` rather than allocating
+    pub fn write_shard_slug(&self, w: &mut impl std::fmt::Write) -> std::fmt::Result {
+        write_shard_slug(w, self.shard_number, self.shard_count)
+    }
+
+    /// The signed distance from `other` to `self` in sort order, treating each id as an 18
+    /// byte big-endian number laid out in the same order as [`Ord`]: tenant id, then shard
+    /// count, then shard number.  The sign therefore agrees with `cmp`, and adjacent shard
+    /// numbers differ by 1.  Useful for estimating how many ids lie between two bounds when
+    /// paginating.  Distances between different tenants are huge, and saturate at the limits
+    /// of `i128`.
+    pub fn sort_distance(&self, other: &TenantShardId) -> i128 {
+        let a = u128::from_be_bytes(self.tenant_id.as_arr());
+        let b = u128::from_be_bytes(other.tenant_id.as_arr());
+        let tenant_distance = if a >= b {
+            i128::try_from(a - b).unwrap_or(i128::MAX)
+        } else {
+            i128::try_from(b - a).map_or(i128::MIN, |d| -d)
+        };
+
+        let shard_position =
+            |id: &TenantShardId| ((id.shard_count.0 as i128) << 8) | id.shard_number.0 as i128;
+        tenant_distance
+            .saturating_mul(1 << 16)
+            .saturating_add(shard_position(self) - shard_position(other))
+    }
+
+    /// Like `to_string()`, but always includes the shard suffix, writing legacy unsharded
+    /// tenants as `-0000`.  For logs, where those should be told apart from single-shard
+    /// tenants at a glance.
+    pub fn to_string_with_suffix(&self) -> String {
+        format!("{}-{}", self.tenant_id, self.shard_slug())
+    }
+
+    /// The name of this shard's directory in the pageserver's local tenants directory.
+    ///
+    /// This is currently the same as the Display form, so that legacy tenants keep their
+    /// pre-sharding directory names, but callers handling local paths should use this rather
+    /// than `to_string()` in case the two conventions diverge.
+    pub fn local_dir_name(&self) -> String {
+        self.to_string()
+    }
+
+    /// Inverse of [`Self::local_dir_name`]
+    pub fn from_local_dir_name(name: &str) -> Result<Self, TenantShardIdParseError> {
+        Self::from_str(name)
+    }
+
+    /// This shard's position among its tenant's shards, in the range `[0, 1)`.  Unsharded
+    /// and single-shard tenants are always at 0.0.
+    pub fn position_in_tenant(&self) -> f64 {
+        if self.shard_count < ShardCount(2) {
+            0.0
+        } else {
+            self.shard_number.0 as f64 / self.shard_count.0 as f64
+        }
+    }
+
+    /// Whether this id's fields are in their canonical form, for validating ids decoded from
+    /// binary sources that may not have been produced by this crate.  Layout V1 reserves no
+    /// bit patterns, so this only checks that the shard number is valid for the shard count.
+    pub fn canonical_bytes(&self) -> bool {
+        ShardIndex::try_from_tenant_shard_id(self).is_ok()
+    }
+
+    /// Whether `s` is a TenantShardId written in its canonical form: the one that `to_string()`
+    /// produces, with lowercase hex and no suffix for legacy unsharded tenants.
+    pub fn is_canonical_str(s: &str) -> bool {
+        Self::from_str(s).map_or(false, |id| id.str_eq(s))
+    }
+
+    /// Whether `s` is this id's canonical string form, i.e. `s == self.to_string()`, without
+    /// allocating or parsing `s`.
+    pub fn str_eq(&self, s: &str) -> bool {
+        let s = s.as_bytes();
+        if self.shard_count.is_unsharded() {
+            s.len() == 32 && hex_eq(s, &self.tenant_id.as_arr())
+        } else {
+            s.len() == 37
+                && hex_eq(&s[0..32], &self.tenant_id.as_arr())
+                && s[32] == b'-'
+                && hex_eq(&s[33..37], &[self.shard_number.0, self.shard_count.0])
+        }
+    }
+
+    /// Equality that treats the legacy (count 0) and single-shard (count 1) forms of a
+    /// tenant's only shard as the same, for deduplication.
+    pub fn eq_ignoring_legacy(&self, other: &TenantShardId) -> bool {
+        if self.tenant_id != other.tenant_id {
+            false
+        } else if self.shard_count < ShardCount(2) && other.shard_count < ShardCount(2) {
+            self.shard_number == other.shard_number
+        } else {
+            self == other
+        }
+    }
+
+    /// This shard's start offset within `window`, such that a tenant's `shards_total` shards
+    /// start evenly spaced across the window: shard k starts at `k/count * window`.  This
+    /// staggers periodic work like compaction across a tenant's shards.
+    pub fn staggered_offset(&self, shards_total: ShardCount, window: Duration) -> Duration {
+        if shards_total < ShardCount(2) {
+            Duration::ZERO
+        } else {
+            // In nanoseconds, so that `window * k` cannot overflow for long windows
+            let nanos = window.as_nanos() * self.shard_number.0 as u128 / shards_total.0 as u128;
+            Duration::new(
+                (nanos / 1_000_000_000) as u64,
+                (nanos % 1_000_000_000) as u32,
+            )
+        }
+    }
+
+    /// A stable 32-bit hash of this id, which unlike `std::hash::Hash` does not vary between
+    /// processes or builds.
+    fn stable_hash(&self) -> u32 {
+        let tenant = self.tenant_id.as_arr();
+        let mut hash = 0;
+        for chunk in tenant.chunks_exact(4) {
+            let word = u32::from_be_bytes(chunk.try_into().unwrap());
+            hash = hash_combine(hash, murmurhash32(word));
+        }
+        let shard = u32::from_be_bytes([0, 0, self.shard_number.0, self.shard_count.0]);
+        hash_combine(hash, murmurhash32(shard))
     }
+
+    /// Deterministically assign this shard to one of `num_buckets` rate limiting buckets.
+    pub fn rate_limit_bucket(&self, num_buckets: NonZeroU32) -> u32 {
+        self.stable_hash() % num_buckets
+    }
+
+    /// Deterministically place this shard on a consistent hashing ring with positions
+    /// `0..ring_size`.  Shards of the same tenant are placed independently, so siblings
+    /// normally land at different positions.
+    pub fn ring_position(&self, ring_size: NonZeroU64) -> u64 {
+        // Scale the hash onto the ring, rather than taking a modulus, so that positions stay
+        // evenly spread for ring sizes that do not divide 2^32.
+        ((self.stable_hash() as u128 * ring_size.get() as u128) >> 32) as u64
+    }
+
+    /// The binary encoding of this id: the tenant id followed by the shard number and count
+    pub fn to_bytes(&self) -> [u8; 18] {
+        let mut packed: [u8; 18] = [0; 18];
+        packed[0..16].clone_from_slice(&self.tenant_id.as_arr());
+        packed[16] = self.shard_number.0;
+        packed[17] = self.shard_count.0;
+        packed
+    }
+
+    /// Whether `b` is this id's binary encoding, for cross-checking ids that were read from
+    /// string and binary sources.
+    pub fn matches_bytes(&self, b: &[u8; 18]) -> bool {
+        self.to_bytes() == *b
+    }
+
+    /// A key for per-shard caches: the binary encoding of this id followed by `sub` in big
+    /// endian, so that byte-wise ordering of cache keys groups them by shard, then by `sub`.
+    pub fn cache_key(&self, sub: u64) -> [u8; 26] {
+        let mut key = [0u8; 26];
+        key[0..18].copy_from_slice(&self.to_bytes());
+        key[18..26].copy_from_slice(&sub.to_be_bytes());
+        key
+    }
+
+    /// Inverse of [`Self::cache_key`]
+    pub fn parse_cache_key(key: &[u8; 26]) -> (TenantShardId, u64) {
+        let id: [u8; 18] = key[0..18].try_into().unwrap();
+        let sub: [u8; 8] = key[18..26].try_into().unwrap();
+        (TenantShardId::from(id), u64::from_be_bytes(sub))
+    }
+
+    /// A key for this id in a byte-keyed KV store shared with other kinds of key: the binary
+    /// encoding, prefixed with a type tag.
+    pub fn kv_key(&self) -> Vec<u8> {
+        let mut key = Vec::with_capacity(19);
+        key.push(TENANT_SHARD_ID_KV_TAG);
+        key.extend_from_slice(&self.to_bytes());
+        key
+    }
+
+    /// Inverse of [`Self::kv_key`]
+    pub fn from_kv_key(key: &[u8]) -> Result<Self, KvKeyError> {
+        match key {
+            [TENANT_SHARD_ID_KV_TAG, rest @ ..] => {
+                let bytes: [u8; 18] = rest
+                    .try_into()
+                    .map_err(|_| KvKeyError::BadLength(key.len()))?;
+                Ok(Self::from(bytes))
+            }
+            [tag, ..] => Err(KvKeyError::WrongTag(*tag)),
+            [] => Err(KvKeyError::BadLength(0)),
+        }
+    }
+
+    /// Whether this id refers to the shard described by `identity`.
+    ///
+    /// Legacy ids (count 0) and single-shard ids (count 1) are interchangeable here: a
+    /// request may carry a legacy id for a tenant whose ShardIdentity has count 1, and
+    /// both describe the one shard that holds all of the tenant's keys.
+    pub fn matches_identity(&self, identity: &ShardIdentity) -> bool {
+        if self.shard_count < ShardCount(2) && identity.count < ShardCount(2) {
+            self.shard_number == ShardNumber(0) && identity.number == ShardNumber(0)
+        } else {
+            self.shard_number == identity.number && self.shard_count == identity.count
+        }
+    }
+}
+
+/// Type tag for TenantShardId keys in a KV store: see [`TenantShardId::kv_key`]
+const TENANT_SHARD_ID_KV_TAG: u8 = 0x01;
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum KvKeyError {
+    #[error("Invalid KV key length {0}")]
+    BadLength(usize),
+    #[error("Unexpected KV key type tag {0:#04x}")]
+    WrongTag(u8),
+}
+
+/// The bytes that differ between two binary-encoded TenantShardIds, as `(offset, a, b)`.
+/// For debugging ids that should match but don't.
+pub fn packed_diff(a: [u8; 18], b: [u8; 18]) -> Vec<(usize, u8, u8)> {
+    a.iter()
+        .zip(b.iter())
+        .enumerate()
+        .filter(|(_, (a, b))| a != b)
+        .map(|(offset, (a, b))| (offset, *a, *b))
+        .collect()
+}
+
+/// Whether `hex` is the lowercase hex encoding of `bytes`
+fn hex_eq(hex: &[u8], bytes: &[u8]) -> bool {
+    const HEX: &[u8] = b"0123456789abcdef";
+    hex.len() == bytes.len() * 2
+        && bytes.iter().zip(hex.chunks_exact(2)).all(|(b, chunk)| {
+            chunk[0] == HEX[(b >> 4) as usize] && chunk[1] == HEX[(b & 0xf) as usize]
+        })
+}
+
+/// Every shard of every tenant in `tenants`, where each tenant has `count` shards.
+pub fn all_tenant_shards(tenants: &[TenantId], count: ShardCount) -> Vec<TenantShardId> {
+    tenants
+        .iter()
+        .flat_map(|tenant_id| TenantShardId::select(*tenant_id, count, ShardSelector::All))
+        .collect()
+}
+
+/// A compact summary of a tenant's shards for log lines, e.g. `1f35...f6fc[0-3/4]` for a
+/// tenant with four shards.  Tenants with a single shard are written without the brackets.
+pub fn shard_map_string(tenant_id: TenantId, count: ShardCount) -> String {
+    let tenant = tenant_id.to_string();
+    let tenant = format!("{}...{}", &tenant[..4], &tenant[tenant.len() - 4..]);
+    if count < ShardCount(2) {
+        tenant
+    } else {
+        format!("{tenant}[0-{}/{}]", count.0 - 1, count.0)
+    }
+}
+
+/// How many of a tenant's `count` shards each of `num_nodes` nodes hosts when the shards are
+/// placed round-robin: the first `count % num_nodes` nodes get one extra.
+pub fn shards_per_node(count: ShardCount, num_nodes: usize) -> Vec<usize> {
+    if num_nodes == 0 {
+        return Vec::new();
+    }
+    // A legacy unsharded tenant has a single shard
+    let count = count.0.max(1) as usize;
+    (0..num_nodes)
+        .map(|node| count / num_nodes + usize::from(node < count % num_nodes))
+        .collect()
+}
+
+/// The distinct shard counts in use across all the tenants in `map`.
+pub fn counts_in_use<T>(map: &BTreeMap<TenantShardId, T>) -> BTreeSet<ShardCount> {
+    map.keys().map(|id| id.shard_count).collect()
 }
 
 impl std::fmt::Display for TenantShardId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if self.shard_count != ShardCount(0) {
+        if !self.shard_count.is_unsharded() {
             write!(
                 f,
                 "{}-{:02x}{:02x}",
@@ -100,30 +697,184 @@ impl std::fmt::Debug for TenantShardId {
     }
 }
 
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum ShardSplitError {
+    #[error("Splitting to the current shard count {} would be a no-op", count.0)]
+    InvalidCount { count: ShardCount },
+    #[error("New shard count {} is not larger than {}", new.0, old.0)]
+    NotLarger { old: ShardCount, new: ShardCount },
+    #[error("New shard count {} is not a multiple of {}", new.0, old.0)]
+    NotMultiple { old: ShardCount, new: ShardCount },
+}
+
+/// The numbers of the shards that replace shard `number` of `count` when splitting into
+/// `new_count` shards: child `n` of the new count descends from parent `n % count`.
+fn split_shard_numbers(
+    number: ShardNumber,
+    count: ShardCount,
+    new_count: ShardCount,
+) -> Result<impl Iterator<Item = ShardNumber>, ShardSplitError> {
+    // A legacy unsharded tenant has a single shard
+    let old_count = count.0.max(1);
+    if new_count.0 <= old_count {
+        return Err(ShardSplitError::NotLarger {
+            old: count,
+            new: new_count,
+        });
+    }
+    if new_count.0 % old_count != 0 {
+        return Err(ShardSplitError::NotMultiple {
+            old: count,
+            new: new_count,
+        });
+    }
+
+    Ok((number.0..new_count.0)
+        .step_by(old_count as usize)
+        .map(ShardNumber))
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum ShardMergeError {
+    #[error("Old shard count {} is not smaller than {}", old.0, count.0)]
+    NotSmaller { count: ShardCount, old: ShardCount },
+    #[error("Old shard count {} does not divide {}", old.0, count.0)]
+    NotDivisor { count: ShardCount, old: ShardCount },
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum TenantShardIdParseError {
+    #[error("Expected 32, 37 or 41 characters, got {got}")]
+    BadLength { got: usize },
+    #[error("Expected '-' between tenant id and shard suffix")]
+    MissingSeparator,
+    #[error("Missing shard suffix")]
+    MissingSuffix,
+    #[error("Invalid generation suffix")]
+    BadGeneration,
+    #[error("Shard number {} is not valid for a shard count of {}", number.0, count.0)]
+    InvalidShard {
+        number: ShardNumber,
+        count: ShardCount,
+    },
+    #[error("Shard {number} of {count} does not fit in a V1 shard suffix: use WideShardIndex")]
+    TooWide { number: u16, count: u16 },
+    #[error("Invalid tenant id: {0}")]
+    BadTenantId(hex::FromHexError),
+    #[error("Invalid shard suffix: {0}")]
+    BadShardSuffix(hex::FromHexError),
+}
+
+/// For callers that predate [`TenantShardIdParseError`] and still expect a hex error.
+impl From<TenantShardIdParseError> for hex::FromHexError {
+    fn from(e: TenantShardIdParseError) -> Self {
+        match e {
+            TenantShardIdParseError::BadLength { .. }
+            | TenantShardIdParseError::MissingSeparator
+            | TenantShardIdParseError::MissingSuffix
+            | TenantShardIdParseError::BadGeneration
+            | TenantShardIdParseError::InvalidShard { .. }
+            | TenantShardIdParseError::TooWide { .. } => hex::FromHexError::InvalidStringLength,
+            TenantShardIdParseError::BadTenantId(e)
+            | TenantShardIdParseError::BadShardSuffix(e) => e,
+        }
+    }
+}
+
+/// Decode a hex shard number and count, choosing the layout by length: a byte each in
+/// [`LAYOUT_V1`] (`070a`), two bytes each in [`LAYOUT_V2`] (`00070011`).
+fn decode_shard_parts(hex: &[u8]) -> Result<(u16, u16), hex::FromHexError> {
+    match hex.len() {
+        4 => {
+            let mut parts = [0u8; 2];
+            hex::decode_to_slice(hex, &mut parts)?;
+            Ok((parts[0] as u16, parts[1] as u16))
+        }
+        8 => {
+            let mut parts = [0u8; 4];
+            hex::decode_to_slice(hex, &mut parts)?;
+            Ok((
+                u16::from_be_bytes([parts[0], parts[1]]),
+                u16::from_be_bytes([parts[2], parts[3]]),
+            ))
+        }
+        _ => Err(hex::FromHexError::InvalidStringLength),
+    }
+}
+
+/// The hex encoded shard number and count, e.g. `0104`, as used in shard suffixes
+fn write_shard_slug(
+    w: &mut impl std::fmt::Write,
+    number: ShardNumber,
+    count: ShardCount,
+) -> std::fmt::Result {
+    write!(w, "{:02x}{:02x}", number.0, count.0)
+}
+
+/// Whether this id belongs to `tenant_id`, ignoring the shard number and count.  Note that
+/// this means a sharded id compares equal to its tenant's TenantId, just like a legacy id.
+impl PartialEq<TenantId> for TenantShardId {
+    fn eq(&self, other: &TenantId) -> bool {
+        self.tenant_id == *other
+    }
+}
+
+impl PartialEq<TenantShardId> for TenantId {
+    fn eq(&self, other: &TenantShardId) -> bool {
+        *self == other.tenant_id
+    }
+}
+
+/// Ordered by tenant, then shard count, then shard number: within a tenant, all the shards
+/// of one split sort together, coarser splits first.
+impl Ord for TenantShardId {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.tenant_id, self.shard_count, self.shard_number).cmp(&(
+            other.tenant_id,
+            other.shard_count,
+            other.shard_number,
+        ))
+    }
+}
+
+impl PartialOrd for TenantShardId {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 impl std::str::FromStr for TenantShardId {
-    type Err = hex::FromHexError;
+    type Err = TenantShardIdParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        // Expect format: 16 byte TenantId, '-', 1 byte shard number, 1 byte shard count
+        // Expect format: 16 byte TenantId, '-', 1 byte shard number, 1 byte shard count (V1),
+        // or 2 bytes each of shard number and count (V2)
         if s.len() == 32 {
             // Legacy case: no shard specified
             Ok(Self {
-                tenant_id: TenantId::from_str(s)?,
+                tenant_id: TenantId::from_str(s).map_err(TenantShardIdParseError::BadTenantId)?,
                 shard_number: ShardNumber(0),
                 shard_count: ShardCount(0),
             })
-        } else if s.len() == 37 {
+        } else if s.len() == 37 || s.len() == 41 {
             let bytes = s.as_bytes();
-            let tenant_id = TenantId::from_hex(&bytes[0..32])?;
-            let mut shard_parts: [u8; 2] = [0u8; 2];
-            hex::decode_to_slice(&bytes[33..37], &mut shard_parts)?;
-            Ok(Self {
-                tenant_id,
-                shard_number: ShardNumber(shard_parts[0]),
-                shard_count: ShardCount(shard_parts[1]),
-            })
+            if bytes[32] != b'-' {
+                return Err(TenantShardIdParseError::MissingSeparator);
+            }
+            let tenant_id =
+                TenantId::from_hex(&bytes[0..32]).map_err(TenantShardIdParseError::BadTenantId)?;
+            let (number, count) = decode_shard_parts(&bytes[33..])
+                .map_err(TenantShardIdParseError::BadShardSuffix)?;
+            match (u8::try_from(number), u8::try_from(count)) {
+                (Ok(number), Ok(count)) => Ok(Self {
+                    tenant_id,
+                    shard_number: ShardNumber(number),
+                    shard_count: ShardCount(count),
+                }),
+                _ => Err(TenantShardIdParseError::TooWide { number, count }),
+            }
         } else {
-            Err(hex::FromHexError::InvalidStringLength)
+            Err(TenantShardIdParseError::BadLength { got: s.len() })
         }
     }
 }
@@ -140,6 +891,23 @@ impl From<[u8; 18]> for TenantShardId {
     }
 }
 
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum TenantShardIdBytesError {
+    #[error("Expected 18 bytes, got {0}")]
+    BadLength(usize),
+}
+
+impl TryFrom<&[u8]> for TenantShardId {
+    type Error = TenantShardIdBytesError;
+
+    fn try_from(b: &[u8]) -> Result<Self, Self::Error> {
+        let bytes: [u8; 18] = b
+            .try_into()
+            .map_err(|_| TenantShardIdBytesError::BadLength(b.len()))?;
+        Ok(Self::from(bytes))
+    }
+}
+
 /// For use within the context of a particular tenant, when we need to know which
 /// shard we're dealing with, but do not need to know the full ShardIdentity (because
 /// we won't be doing any page->shard mapping), and do not need to know the fully qualified
@@ -151,21 +919,94 @@ pub struct ShardIndex {
 }
 
 impl ShardIndex {
-    pub fn new(number: ShardNumber, count: ShardCount) -> Self {
+    pub const fn new(number: ShardNumber, count: ShardCount) -> Self {
         Self {
             shard_number: number,
             shard_count: count,
         }
     }
-    pub fn unsharded() -> Self {
+    pub const fn unsharded() -> Self {
         Self {
             shard_number: ShardNumber(0),
             shard_count: ShardCount(0),
         }
     }
 
-    pub fn is_unsharded(&self) -> bool {
-        self.shard_number == ShardNumber(0) && self.shard_count == ShardCount(0)
+    pub const fn is_unsharded(&self) -> bool {
+        // Compare the inner integers: PartialEq is not usable in const fns
+        self.shard_number.0 == 0 && self.shard_count.0 == 0
+    }
+
+    /// Inverse of [`Self::get_suffix`]: an empty string is the unsharded index, and anything
+    /// else must be a `-` followed by the hex encoded index.
+    pub fn from_suffix(s: &str) -> Result<ShardIndex, ShardIndexParseError> {
+        if s.is_empty() {
+            return Ok(Self::unsharded());
+        }
+
+        let index = s
+            .strip_prefix('-')
+            .ok_or(ShardIndexParseError::MissingSeparator)?
+            .parse::<ShardIndex>()?;
+        if index.is_unsharded() {
+            // get_suffix never writes this: the unsharded suffix is empty
+            Err(ShardIndexParseError::ExplicitUnsharded)
+        } else {
+            Ok(index)
+        }
+    }
+
+    /// Validate an index that was parsed from an explicit shard suffix, e.g. in a remote
+    /// storage path.  Legacy unsharded tenants are written without a suffix, so `0000` is
+    /// never legal in this form, and the shard number must be within the count.
+    pub fn validate_for_storage(&self) -> Result<(), ShardConfigError> {
+        if self.shard_count.is_unsharded() {
+            Err(ShardConfigError::InvalidCount)
+        } else if self.shard_number.0 >= self.shard_count.0 {
+            Err(ShardConfigError::InvalidNumber)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// A stable color for this shard number, for dashboards.  Hues are spaced by the golden
+    /// ratio so that consecutive shard numbers get visibly different colors.
+    pub fn color_rgb(&self) -> (u8, u8, u8) {
+        const GOLDEN_RATIO_CONJUGATE: f64 = 0.618_033_988_749_895;
+        const SATURATION: f64 = 0.65;
+        const VALUE: f64 = 0.95;
+
+        let hue = (self.shard_number.0 as f64 * GOLDEN_RATIO_CONJUGATE).fract() * 6.0;
+        let f = hue.fract();
+        let p = VALUE * (1.0 - SATURATION);
+        let q = VALUE * (1.0 - f * SATURATION);
+        let t = VALUE * (1.0 - (1.0 - f) * SATURATION);
+        let (r, g, b) = match hue as u8 {
+            0 => (VALUE, t, p),
+            1 => (q, VALUE, p),
+            2 => (p, VALUE, t),
+            3 => (p, q, VALUE),
+            4 => (t, p, VALUE),
+            _ => (VALUE, p, q),
+        };
+        let scale = |c: f64| (c * 255.0).round() as u8;
+        (scale(r), scale(g), scale(b))
+    }
+
+    /// Build the ShardIndex for a TenantShardId, checking that its shard number is
+    /// within its shard count.  Legacy ids (count 0) must have shard number 0.
+    pub fn try_from_tenant_shard_id(id: &TenantShardId) -> Result<ShardIndex, ShardConfigError> {
+        let consistent = if id.shard_count.is_unsharded() {
+            id.shard_number == ShardNumber(0)
+        } else {
+            id.shard_number.0 < id.shard_count.0
+        };
+
+        if consistent {
+            Ok(Self::new(id.shard_number, id.shard_count))
+        } else {
+            Err(ShardConfigError::InvalidNumber)
+        }
     }
 
     /// For use in constructing remote storage paths: concatenate this with a TenantId
@@ -177,11 +1018,47 @@ impl ShardIndex {
         if self.is_unsharded() {
             "".to_string()
         } else {
-            format!("-{:02x}{:02x}", self.shard_number.0, self.shard_count.0)
+            let mut suffix = String::with_capacity(5);
+            suffix.push('-');
+            write_shard_slug(&mut suffix, self.shard_number, self.shard_count)
+                .expect("writing to a String cannot fail");
+            suffix
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum ShardIndexParseError {
+    #[error("Shard suffix does not start with '-'")]
+    MissingSeparator,
+    #[error("Invalid shard index: {0}")]
+    BadIndex(hex::FromHexError),
+    #[error("Unsharded index written with an explicit suffix")]
+    ExplicitUnsharded,
+    #[error("Shard number not valid for shard count: {0}")]
+    InvalidShard(ShardConfigError),
+    #[error("Shard {number} of {count} does not fit in a ShardIndex: use WideShardIndex")]
+    TooWide { number: u16, count: u16 },
+}
+
+impl From<ShardIndexParseError> for hex::FromHexError {
+    fn from(e: ShardIndexParseError) -> Self {
+        match e {
+            ShardIndexParseError::BadIndex(e) => e,
+            ShardIndexParseError::MissingSeparator
+            | ShardIndexParseError::ExplicitUnsharded
+            | ShardIndexParseError::InvalidShard(_)
+            | ShardIndexParseError::TooWide { .. } => hex::FromHexError::InvalidStringLength,
         }
     }
 }
 
+impl Default for ShardIndex {
+    fn default() -> Self {
+        Self::unsharded()
+    }
+}
+
 impl std::fmt::Display for ShardIndex {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{:02x}{:02x}", self.shard_number.0, self.shard_count.0)
@@ -196,20 +1073,19 @@ impl std::fmt::Debug for ShardIndex {
 }
 
 impl std::str::FromStr for ShardIndex {
-    type Err = hex::FromHexError;
+    type Err = ShardIndexParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        // Expect format: 1 byte shard number, 1 byte shard count
-        if s.len() == 4 {
-            let bytes = s.as_bytes();
-            let mut shard_parts: [u8; 2] = [0u8; 2];
-            hex::decode_to_slice(bytes, &mut shard_parts)?;
-            Ok(Self {
-                shard_number: ShardNumber(shard_parts[0]),
-                shard_count: ShardCount(shard_parts[1]),
-            })
-        } else {
-            Err(hex::FromHexError::InvalidStringLength)
+        // Expect format: 1 byte shard number, 1 byte shard count (V1), or 2 bytes of each (V2).
+        // V2 strings whose values do not fit in a byte need a WideShardIndex.
+        let (number, count) =
+            decode_shard_parts(s.as_bytes()).map_err(ShardIndexParseError::BadIndex)?;
+        match (u8::try_from(number), u8::try_from(count)) {
+            (Ok(number), Ok(count)) => Ok(Self {
+                shard_number: ShardNumber(number),
+                shard_count: ShardCount(count),
+            }),
+            _ => Err(ShardIndexParseError::TooWide { number, count }),
         }
     }
 }
@@ -223,6 +1099,203 @@ impl From<[u8; 2]> for ShardIndex {
     }
 }
 
+impl From<&TenantShardId> for ShardIndex {
+    fn from(id: &TenantShardId) -> Self {
+        Self {
+            shard_number: id.shard_number,
+            shard_count: id.shard_count,
+        }
+    }
+}
+
+/// Like [`ShardIndex`], but able to describe tenants with more than 255 shards.
+///
+/// The layout determines the encoding.  In [`LAYOUT_V1`], the number and count are a byte
+/// each, and the encodings are identical to ShardIndex's: `070a` as a string, two bytes in
+/// binary.  In [`LAYOUT_V2`] they are two bytes each: `0007012c` as a string, four bytes in
+/// binary.  Decoders tell the two apart by length.  The [`ShardIndex`] and [`TenantShardId`]
+/// parsers accept V2 strings too, as long as the values fit in a byte, but always write V1.
+///
+/// Callers choose a layout with [`Self::new`], which uses V1 whenever the count fits in
+/// a [`ShardCount`], so that V1 decoders can still read the result.  [`Self::new_v2`]
+/// opts in to V2 regardless of count.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub struct WideShardIndex {
+    layout: ShardLayout,
+    shard_number: u16,
+    shard_count: u16,
+}
+
+impl WideShardIndex {
+    /// Shard `number` of `count`, in the narrowest layout that can encode it.  The number
+    /// must be below the count, except in the unsharded index (0, 0).
+    pub fn new(number: u16, count: u16) -> Result<Self, ShardConfigError> {
+        let layout = ShardLayout::upgrade_needed_for(count).unwrap_or(LAYOUT_V1);
+        Self::with_layout(layout, number, count)
+    }
+
+    /// Like [`Self::new`], but always in [`LAYOUT_V2`]
+    pub fn new_v2(number: u16, count: u16) -> Result<Self, ShardConfigError> {
+        Self::with_layout(LAYOUT_V2, number, count)
+    }
+
+    fn with_layout(layout: ShardLayout, number: u16, count: u16) -> Result<Self, ShardConfigError> {
+        if number >= count.max(1) {
+            return Err(ShardConfigError::InvalidNumber);
+        }
+        Ok(Self {
+            layout,
+            shard_number: number,
+            shard_count: count,
+        })
+    }
+
+    pub fn layout(&self) -> ShardLayout {
+        self.layout
+    }
+
+    pub fn shard_number(&self) -> u16 {
+        self.shard_number
+    }
+
+    pub fn shard_count(&self) -> u16 {
+        self.shard_count
+    }
+
+    /// Like [`ShardIndex::get_suffix`]: empty for the unsharded V1 index.
+    pub fn get_suffix(&self) -> String {
+        if self.layout == LAYOUT_V1 && self.shard_number == 0 && self.shard_count == 0 {
+            "".to_string()
+        } else {
+            format!("-{self}")
+        }
+    }
+
+    /// The binary encoding: two bytes in V1, four in V2
+    pub fn to_bytes(&self) -> Vec<u8> {
+        if self.layout == LAYOUT_V1 {
+            vec![self.shard_number as u8, self.shard_count as u8]
+        } else {
+            let [n0, n1] = self.shard_number.to_be_bytes();
+            let [c0, c1] = self.shard_count.to_be_bytes();
+            vec![n0, n1, c0, c1]
+        }
+    }
+
+    /// Inverse of [`Self::to_bytes`], choosing the layout by length
+    pub fn from_bytes(b: &[u8]) -> Result<Self, ShardIndexParseError> {
+        let index = match b {
+            [n, c] => Self::new(*n as u16, *c as u16),
+            [n0, n1, c0, c1] => Self::new_v2(
+                u16::from_be_bytes([*n0, *n1]),
+                u16::from_be_bytes([*c0, *c1]),
+            ),
+            _ => {
+                return Err(ShardIndexParseError::BadIndex(
+                    hex::FromHexError::InvalidStringLength,
+                ))
+            }
+        };
+        index.map_err(ShardIndexParseError::InvalidShard)
+    }
+}
+
+impl TryFrom<ShardIndex> for WideShardIndex {
+    type Error = ShardConfigError;
+
+    fn try_from(index: ShardIndex) -> Result<Self, Self::Error> {
+        Self::new(index.shard_number.0 as u16, index.shard_count.0 as u16)
+    }
+}
+
+impl TryFrom<WideShardIndex> for ShardIndex {
+    type Error = ShardConfigError;
+
+    fn try_from(index: WideShardIndex) -> Result<Self, Self::Error> {
+        let shard_count =
+            u8::try_from(index.shard_count).map_err(|_| ShardConfigError::InvalidCount)?;
+        let shard_number =
+            u8::try_from(index.shard_number).map_err(|_| ShardConfigError::InvalidNumber)?;
+        Ok(Self::new(
+            ShardNumber(shard_number),
+            ShardCount(shard_count),
+        ))
+    }
+}
+
+impl std::fmt::Display for WideShardIndex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.layout == LAYOUT_V1 {
+            write!(f, "{:02x}{:02x}", self.shard_number, self.shard_count)
+        } else {
+            write!(f, "{:04x}{:04x}", self.shard_number, self.shard_count)
+        }
+    }
+}
+
+impl std::str::FromStr for WideShardIndex {
+    type Err = ShardIndexParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (number, count) =
+            decode_shard_parts(s.as_bytes()).map_err(ShardIndexParseError::BadIndex)?;
+        let index = if s.len() == 4 {
+            Self::new(number, count)
+        } else {
+            Self::new_v2(number, count)
+        };
+        index.map_err(ShardIndexParseError::InvalidShard)
+    }
+}
+
+/// Wrapper for (de)serializing a ShardIndex as an array of its two integer fields, e.g.
+/// `[13,17]`, in all encodings.  ShardIndex itself uses a hex string (`"0d11"`) in human
+/// readable encodings: this is for consumers that would rather not parse hex.
+///
+/// To use this for a ShardIndex field, annotate it with
+/// `#[serde(with = "pageserver_api::shard::shard_index_as_array")]`.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub struct ShardIndexArray(pub ShardIndex);
+
+impl Serialize for ShardIndexArray {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        [self.0.shard_number.0, self.0.shard_count.0].serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ShardIndexArray {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let packed: [u8; 2] = Deserialize::deserialize(deserializer)?;
+        Ok(Self(ShardIndex::from(packed)))
+    }
+}
+
+/// For use with `#[serde(with = ...)]`: see [`ShardIndexArray`]
+pub mod shard_index_as_array {
+    use super::{ShardIndex, ShardIndexArray};
+    use serde::{Deserialize, Serialize};
+
+    pub fn serialize<S>(index: &ShardIndex, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        ShardIndexArray(*index).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<ShardIndex, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        ShardIndexArray::deserialize(deserializer).map(|a| a.0)
+    }
+}
+
 impl Serialize for TenantShardId {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -231,12 +1304,7 @@ impl Serialize for TenantShardId {
         if serializer.is_human_readable() {
             serializer.collect_str(self)
         } else {
-            let mut packed: [u8; 18] = [0; 18];
-            packed[0..16].clone_from_slice(&self.tenant_id.as_arr());
-            packed[16] = self.shard_number.0;
-            packed[17] = self.shard_count.0;
-
-            packed.serialize(serializer)
+            self.to_bytes().serialize(serializer)
         }
     }
 }
@@ -293,22 +1361,144 @@ impl<'de> Deserialize<'de> for TenantShardId {
     }
 }
 
+/// Wrapper for deserializing a TenantShardId that older persisted state may have written with
+/// its fields spelled out, e.g. `{"tenant_id": "...", "shard_number": 7, "shard_count": 10}`.
+/// Human readable encodings accept either that or the usual hex string, and serialization
+/// always writes the hex string.  TenantShardId itself only accepts the string, so that it
+/// also works in human readable formats that are not self-describing.
+///
+/// To use this for a TenantShardId field, annotate it with
+/// `#[serde(with = "pageserver_api::shard::tenant_shard_id_compat")]`.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub struct TenantShardIdCompat(pub TenantShardId);
+
+impl Serialize for TenantShardIdCompat {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for TenantShardIdCompat {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Compact(TenantShardId),
+            Fields {
+                tenant_id: TenantId,
+                shard_number: ShardNumber,
+                shard_count: ShardCount,
+            },
+        }
+
+        if !deserializer.is_human_readable() {
+            // The binary form has no older spelling
+            return TenantShardId::deserialize(deserializer).map(Self);
+        }
+
+        Ok(Self(match Repr::deserialize(deserializer)? {
+            Repr::Compact(id) => id,
+            Repr::Fields {
+                tenant_id,
+                shard_number,
+                shard_count,
+            } => TenantShardId {
+                tenant_id,
+                shard_number,
+                shard_count,
+            },
+        }))
+    }
+}
+
+/// For use with `#[serde(with = ...)]`: see [`TenantShardIdCompat`]
+pub mod tenant_shard_id_compat {
+    use super::{TenantShardId, TenantShardIdCompat};
+    use serde::{Deserialize, Serialize};
+
+    pub fn serialize<S>(id: &TenantShardId, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        TenantShardIdCompat(*id).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<TenantShardId, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        TenantShardIdCompat::deserialize(deserializer).map(|c| c.0)
+    }
+}
+
 /// Stripe size in number of pages
 #[derive(Clone, Copy, Serialize, Deserialize, Eq, PartialEq, Debug)]
 pub struct ShardStripeSize(pub u32);
 
+impl ShardStripeSize {
+    /// A stripe of `pages` pages, which must be nonzero.  Any nonzero size is valid in
+    /// layout V1: the power-of-two requirement of V2 is checked separately, by
+    /// [`ShardIdentity::validate_layout_constraints`].
+    pub fn new(pages: u32) -> Result<ShardStripeSize, ShardConfigError> {
+        if pages == 0 {
+            Err(ShardConfigError::InvalidStripeSize)
+        } else {
+            Ok(Self(pages))
+        }
+    }
+
+    /// The stripe size of newly created identities: [`DEFAULT_STRIPE_SIZE`]
+    pub const fn default_value() -> ShardStripeSize {
+        DEFAULT_STRIPE_SIZE
+    }
+
+    /// The size of a stripe in bytes, for pages of `page_size` bytes
+    pub const fn in_bytes(&self, page_size: u32) -> u64 {
+        self.0 as u64 * page_size as u64
+    }
+}
+
 /// Layout version: for future upgrades where we might change how the key->shard mapping works
 #[derive(Clone, Copy, Serialize, Deserialize, Eq, PartialEq, Debug)]
 pub struct ShardLayout(u8);
 
-const LAYOUT_V1: ShardLayout = ShardLayout(1);
+pub const LAYOUT_V1: ShardLayout = ShardLayout(1);
+
+/// For tenants with more shards than fit in V1's 8-bit ShardCount: shard numbers and counts
+/// are 16 bits wide.  So far this only affects the encoding of [`WideShardIndex`]: there is no
+/// V2 key mapping yet, so [`ShardIdentity`] does not accept this layout.
+pub const LAYOUT_V2: ShardLayout = ShardLayout(2);
+
+impl ShardLayout {
+    /// The layout of newly created identities: [`LAYOUT_V1`]
+    pub const fn current() -> ShardLayout {
+        LAYOUT_V1
+    }
+
+    /// The layout that a tenant would need to move to in order to have `count` shards, or
+    /// None if the current layout (V1) can represent that many shards.
+    pub fn upgrade_needed_for(count: u16) -> Option<ShardLayout> {
+        if count > ShardCount::MAX.0 as u16 {
+            Some(LAYOUT_V2)
+        } else {
+            None
+        }
+    }
+}
 
 /// Default stripe size in pages: 256MiB divided by 8kiB page size.
-const DEFAULT_STRIPE_SIZE: ShardStripeSize = ShardStripeSize(256 * 1024 / 8);
+pub const DEFAULT_STRIPE_SIZE: ShardStripeSize = ShardStripeSize(256 * 1024 / 8);
 
 /// The ShardIdentity contains the information needed for one member of map
 /// to resolve a key to a shard, and then check whether that shard is ==self.
 #[derive(Clone, Copy, Serialize, Deserialize, Eq, PartialEq, Debug)]
+#[serde(try_from = "UncheckedShardIdentity")]
 pub struct ShardIdentity {
     pub layout: ShardLayout,
     pub number: ShardNumber,
@@ -316,6 +1506,30 @@ pub struct ShardIdentity {
     pub stripe_size: ShardStripeSize,
 }
 
+/// Deserialization target for ShardIdentity, which checks its layout's constraints before
+/// accepting it.
+#[derive(Deserialize)]
+struct UncheckedShardIdentity {
+    layout: ShardLayout,
+    number: ShardNumber,
+    count: ShardCount,
+    stripe_size: ShardStripeSize,
+}
+
+impl Default for ShardIdentity {
+    fn default() -> Self {
+        Self::unsharded()
+    }
+}
+
+impl TryFrom<UncheckedShardIdentity> for ShardIdentity {
+    type Error = ShardConfigError;
+
+    fn try_from(value: UncheckedShardIdentity) -> Result<Self, Self::Error> {
+        Self::checked(value.layout, value.number, value.count, value.stripe_size)
+    }
+}
+
 #[derive(thiserror::Error, Debug, PartialEq, Eq)]
 pub enum ShardConfigError {
     #[error("Invalid shard count")]
@@ -324,6 +1538,89 @@ pub enum ShardConfigError {
     InvalidNumber,
     #[error("Invalid stripe size")]
     InvalidStripeSize,
+    #[error("Invalid shard layout")]
+    InvalidLayout,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum ShardSetError {
+    #[error("Empty shard set")]
+    Empty,
+    #[error("Shard identities disagree on count, stripe size or layout")]
+    Inconsistent,
+    #[error("Expected {expected} shards, found {found}")]
+    WrongSize { expected: usize, found: usize },
+    #[error("Shard {0:?} is missing")]
+    Missing(ShardNumber),
+    #[error("Invalid shard identity: {0}")]
+    InvalidIdentity(ShardConfigError),
+}
+
+/// Check that `identities` is the complete set of shards for one tenant: one identity per
+/// shard number, all agreeing on count, stripe size and layout.  A legacy tenant's set is
+/// its single unsharded identity.  The identities may be in any order.
+pub fn validate_shard_set(identities: &[ShardIdentity]) -> Result<(), ShardSetError> {
+    let Some(first) = identities.first() else {
+        return Err(ShardSetError::Empty);
+    };
+
+    if identities.iter().any(|i| {
+        i.count != first.count || i.stripe_size != first.stripe_size || i.layout != first.layout
+    }) {
+        return Err(ShardSetError::Inconsistent);
+    }
+
+    let expected = first.count.0.max(1) as usize;
+    if identities.len() != expected {
+        return Err(ShardSetError::WrongSize {
+            expected,
+            found: identities.len(),
+        });
+    }
+
+    // With the right number of identities, every shard is present iff there are no duplicates
+    let present: BTreeSet<ShardNumber> = identities.iter().map(|i| i.number).collect();
+    match (0..expected as u8)
+        .map(ShardNumber)
+        .find(|n| !present.contains(n))
+    {
+        Some(missing) => Err(ShardSetError::Missing(missing)),
+        None => Ok(()),
+    }
+}
+
+/// Routes keys to the identity of the shard that owns them, for a complete set of shards.
+pub struct ShardRoutingTable {
+    router: ShardRouter,
+    /// Indexed by shard number
+    identities: Vec<ShardIdentity>,
+}
+
+impl ShardRoutingTable {
+    /// `identities` must be a complete shard set: see [`validate_shard_set`]
+    pub fn build(mut identities: Vec<ShardIdentity>) -> Result<Self, ShardSetError> {
+        validate_shard_set(&identities)?;
+        let router = ShardRouter::new(&identities[0]).map_err(ShardSetError::InvalidIdentity)?;
+        identities.sort_by_key(|i| i.number);
+        Ok(Self { router, identities })
+    }
+
+    pub fn route(&self, key: &Key) -> &ShardIdentity {
+        &self.identities[self.router.route(key).0 as usize]
+    }
+}
+
+/// A tenant's complete shard layout, for debugging dumps.
+#[derive(Clone, Serialize, Deserialize, Eq, PartialEq, Debug)]
+pub struct ShardBundle {
+    pub tenant_id: TenantId,
+    pub identities: Vec<ShardIdentity>,
+}
+
+impl ShardBundle {
+    pub fn validate(&self) -> Result<(), ShardSetError> {
+        validate_shard_set(&self.identities)
+    }
 }
 
 impl ShardIdentity {
@@ -343,6 +1640,19 @@ impl ShardIdentity {
         self.number == ShardNumber(0) && self.count == ShardCount(0)
     }
 
+    /// This shard's index within its tenant
+    pub fn index(&self) -> ShardIndex {
+        ShardIndex::new(self.number, self.count)
+    }
+
+    /// The same as [`TenantShardId::shard_slug`] for this shard, e.g. for metric labels
+    pub fn shard_slug(&self) -> String {
+        let mut slug = String::with_capacity(4);
+        write_shard_slug(&mut slug, self.number, self.count)
+            .expect("writing to a String cannot fail");
+        slug
+    }
+
     /// Count must be nonzero, and number must be < count. To construct
     /// the legacy case (count==0), use Self::unsharded instead.
     pub fn new(
@@ -350,7 +1660,7 @@ impl ShardIdentity {
         count: ShardCount,
         stripe_size: ShardStripeSize,
     ) -> Result<Self, ShardConfigError> {
-        if count.0 == 0 {
+        if count.is_unsharded() {
             Err(ShardConfigError::InvalidCount)
         } else if number.0 > count.0 - 1 {
             Err(ShardConfigError::InvalidNumber)
@@ -365,248 +1675,3934 @@ impl ShardIdentity {
             })
         }
     }
-}
 
-impl Serialize for ShardIndex {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        if serializer.is_human_readable() {
-            serializer.collect_str(self)
-        } else {
-            // Binary encoding is not used in index_part.json, but is included in anticipation of
-            // switching various structures (e.g. inter-process communication, remote metadata) to more
-            // compact binary encodings in future.
-            let mut packed: [u8; 2] = [0; 2];
-            packed[0] = self.shard_number.0;
-            packed[1] = self.shard_count.0;
-            packed.serialize(serializer)
-        }
+    /// Pack this identity into a u64: layout, number and count in the top three bytes, and
+    /// stripe size in the bottom four.
+    pub fn to_u64(&self) -> u64 {
+        ((self.layout.0 as u64) << 56)
+            | ((self.number.0 as u64) << 48)
+            | ((self.count.0 as u64) << 40)
+            | self.stripe_size.0 as u64
     }
-}
 
-impl<'de> Deserialize<'de> for ShardIndex {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        struct IdVisitor {
-            is_human_readable_deserializer: bool,
+    /// Inverse of [`Self::to_u64`], accepting only values that describe a valid identity in
+    /// a known layout.  The legacy unsharded identity is accepted as a special case.
+    pub fn from_u64_strict(v: u64) -> Result<Self, ShardConfigError> {
+        if (v >> 32) & 0xff != 0 {
+            // Unused bits: the stripe size would not fit in a u32
+            return Err(ShardConfigError::InvalidStripeSize);
         }
+        Self::checked(
+            ShardLayout((v >> 56) as u8),
+            ShardNumber((v >> 48) as u8),
+            ShardCount((v >> 40) as u8),
+            ShardStripeSize(v as u32),
+        )
+    }
 
-        impl<'de> serde::de::Visitor<'de> for IdVisitor {
-            type Value = ShardIndex;
-
-            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-                if self.is_human_readable_deserializer {
-                    formatter.write_str("value in form of hex string")
-                } else {
-                    formatter.write_str("value in form of integer array([u8; 2])")
-                }
-            }
-
-            fn visit_seq<A>(self, seq: A) -> Result<Self::Value, A::Error>
-            where
-                A: serde::de::SeqAccess<'de>,
-            {
-                let s = serde::de::value::SeqAccessDeserializer::new(seq);
-                let id: [u8; 2] = Deserialize::deserialize(s)?;
-                Ok(ShardIndex::from(id))
+    /// Like [`Self::new`], but also accepts the legacy unsharded identity, and checks the
+    /// constraints of `layout`: for identities from outside, e.g. deserialized ones.
+    fn checked(
+        layout: ShardLayout,
+        number: ShardNumber,
+        count: ShardCount,
+        stripe_size: ShardStripeSize,
+    ) -> Result<Self, ShardConfigError> {
+        let identity = if number == ShardNumber(0) && count == ShardCount(0) {
+            Self {
+                stripe_size: ShardStripeSize::new(stripe_size.0)?,
+                ..Self::unsharded()
             }
+        } else {
+            Self::new(number, count, stripe_size)?
+        };
+        let identity = Self { layout, ..identity };
+        identity.validate_layout_constraints()?;
+        Ok(identity)
+    }
 
-            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
-            where
-                E: serde::de::Error,
-            {
-                ShardIndex::from_str(v).map_err(E::custom)
-            }
+    /// The identities of the shards that replace this one when its tenant is split into
+    /// `new_count` shards.  Stripe size and layout are carried over unchanged, so that each
+    /// key this shard owned is owned by exactly one of the children.
+    pub fn split(&self, new_count: ShardCount) -> Result<Vec<ShardIdentity>, ShardSplitError> {
+        if self.count.is_noop_split(new_count) {
+            return Err(ShardSplitError::InvalidCount { count: new_count });
         }
 
-        if deserializer.is_human_readable() {
-            deserializer.deserialize_str(IdVisitor {
-                is_human_readable_deserializer: true,
+        Ok(split_shard_numbers(self.number, self.count, new_count)?
+            .map(|number| Self {
+                number,
+                count: new_count,
+                ..*self
             })
+            .collect())
+    }
+
+    /// Like [`Self::get_shard_number`], but returns the owning shard's full index
+    pub fn resolve_index(&self, key: &Key) -> ShardIndex {
+        if self.is_unsharded() {
+            ShardIndex::unsharded()
         } else {
-            deserializer.deserialize_tuple(
-                2,
-                IdVisitor {
+            ShardIndex::new(self.get_shard_number(key), self.count)
+        }
+    }
+
+    /// The id of the shard of `tenant_id` that owns `key`.  For unsharded tenants this is the
+    /// legacy id.
+    pub fn resolve(&self, tenant_id: TenantId, key: &Key) -> TenantShardId {
+        let index = self.resolve_index(key);
+        TenantShardId {
+            tenant_id,
+            shard_number: index.shard_number,
+            shard_count: index.shard_count,
+        }
+    }
+
+    /// Build the identity of the shard at `index`, taking the stripe size and layout from
+    /// `reference`: the identity of another shard in the same tenant.  The index must have
+    /// the same shard count as the reference.
+    pub fn from_index_like(
+        index: ShardIndex,
+        reference: &ShardIdentity,
+    ) -> Result<ShardIdentity, ShardConfigError> {
+        if index.shard_count != reference.count {
+            return Err(ShardConfigError::InvalidCount);
+        }
+
+        if index.is_unsharded() {
+            return Ok(Self {
+                number: ShardNumber(0),
+                count: ShardCount(0),
+                ..*reference
+            });
+        }
+
+        Ok(Self {
+            layout: reference.layout,
+            ..Self::new(index.shard_number, index.shard_count, reference.stripe_size)?
+        })
+    }
+
+    /// Whether splitting each of this tenant's shards into `factor` shards would exceed the
+    /// maximum shard count, so that the split would be rejected.
+    pub fn would_overflow_on_split(&self, factor: u8) -> bool {
+        self.count.0.max(1).checked_mul(factor).is_none()
+    }
+
+    /// Check the constraints that this identity's layout places on its other fields.  V1
+    /// places none.  V2 requires a power-of-two stripe size, but is rejected regardless
+    /// until it has its own key mapping: until then its keys would be routed as V1's.
+    /// Unknown layouts are rejected, as we would not know how to map keys for them.
+    pub fn validate_layout_constraints(&self) -> Result<(), ShardConfigError> {
+        match self.layout {
+            LAYOUT_V1 => Ok(()),
+            LAYOUT_V2 if !self.stripe_size.0.is_power_of_two() => {
+                Err(ShardConfigError::InvalidStripeSize)
+            }
+            _ => Err(ShardConfigError::InvalidLayout),
+        }
+    }
+
+    /// Whether `key` is one of the tenant-wide keys that always live on shard zero rather
+    /// than being distributed by hash.  That is everything but relation blocks: in the
+    /// layout of pgdatadir_mapping.rs, the database and relation directories and relation
+    /// sizes (0x00), SLRUs (0x01), twophase files (0x02), and the misc section (0x03, e.g.
+    /// the control file and checkpoint).
+    pub fn is_global_key(key: &Key) -> bool {
+        !key.is_rel_block_key()
+    }
+
+    /// Whether `key` is metadata rather than relation block data: the same as
+    /// [`Self::is_global_key`].
+    pub fn is_metadata_key(key: &Key) -> bool {
+        Self::is_global_key(key)
+    }
+
+    /// Return the shard that owns `key` in this identity's layout.  This does not depend
+    /// on `self.number`: every member of a shard set agrees on where a key lives.
+    pub fn get_shard_number(&self, key: &Key) -> ShardNumber {
+        key_to_shard_number(self.count, self.stripe_size, key)
+    }
+
+    /// Return true if the key should be ingested by this shard
+    pub fn is_key_local(&self, key: &Key) -> bool {
+        self.number == self.get_shard_number(key)
+    }
+
+    /// The shard responsible for a relation's metadata (e.g. its size), as opposed to its
+    /// blocks.  This is always shard zero.
+    pub fn shard_for_relation_metadata(&self) -> ShardNumber {
+        ShardNumber(0)
+    }
+
+    /// The shard that owns block `blkno` of relation `rel`
+    pub fn shard_for_relation_block(&self, rel: RelTag, blkno: u32) -> ShardNumber {
+        self.get_shard_number(&rel_block_to_key(rel, blkno))
+    }
+
+    /// The shard that owns the last block of relation `rel`, which has `nblocks` blocks: the
+    /// one that truncation of the relation's tail starts on.  An empty relation has no blocks,
+    /// so this is the shard holding its metadata.
+    pub fn shard_for_rel_end(&self, rel: RelTag, nblocks: u32) -> ShardNumber {
+        match nblocks.checked_sub(1) {
+            Some(last) => self.shard_for_relation_block(rel, last),
+            None => self.shard_for_relation_metadata(),
+        }
+    }
+
+    /// The shards that own any of blocks `0..nblocks` of relation `rel`, in a tenant with
+    /// `count` shards: those that a scan of the whole relation must consult.
+    pub fn shards_for_relation(
+        count: ShardCount,
+        stripe_size: ShardStripeSize,
+        rel: RelTag,
+        nblocks: u32,
+    ) -> Result<BTreeSet<ShardNumber>, ShardConfigError> {
+        let stripe_size = ShardStripeSize::new(stripe_size.0)?;
+        let mut shards = BTreeSet::new();
+        // Each stripe lives on a single shard, so check one block from each
+        for blkno in (0..nblocks).step_by(stripe_size.0 as usize) {
+            shards.insert(key_to_shard_number(
+                count,
+                stripe_size,
+                &rel_block_to_key(rel, blkno),
+            ));
+            if shards.len() == count.0.max(1) as usize {
+                break;
+            }
+        }
+        Ok(shards)
+    }
+
+    /// Whether `self` and `other` place stripes compatibly: they have the same stripe size,
+    /// and one shard count is a multiple of the other, so that each shard of the larger count
+    /// holds a subset of the keys of one shard of the smaller count.
+    pub fn stripe_aligned_with(&self, other: &ShardIdentity) -> bool {
+        // Legacy unsharded identities behave like a count of 1
+        let a = self.count.0.max(1);
+        let b = other.count.0.max(1);
+        self.stripe_size == other.stripe_size && (a % b == 0 || b % a == 0)
+    }
+
+    /// The shard that owns logical slot `slot` of a structure that is striped across shards by
+    /// slot number rather than by Key (e.g. SLRU segments): runs of `stripe_size` consecutive
+    /// slots are assigned to shards in turn.
+    pub fn shard_for_slot(&self, slot: u64) -> ShardNumber {
+        if self.count < ShardCount(2) {
+            return ShardNumber(0);
+        }
+
+        let stripe = slot / self.stripe_size.0 as u64;
+        ShardNumber((stripe % self.count.0 as u64) as u8)
+    }
+
+    /// Estimate the fraction of `rel`'s keys that are local to this shard from a histogram in
+    /// which bucket `i` counts the keys in the relation's stripe `i`.  Each stripe's owner is
+    /// found by routing its first block, so this avoids iterating over the keys themselves.
+    ///
+    /// The relation is taken separately because a stripe index alone does not determine its
+    /// owner: routing hashes the relation number together with the stripe, so stripe `i` of
+    /// two relations generally lands on different shards.  Callers with histograms spanning
+    /// several relations should call this once per relation.
+    pub fn locality_from_histogram(&self, rel: RelTag, histogram: &[u64]) -> f64 {
+        let total: u64 = histogram.iter().sum();
+        if total == 0 {
+            return 0.0;
+        }
+
+        let stripe_size = self.stripe_size.0 as u64;
+        let local: u64 = histogram
+            .iter()
+            .enumerate()
+            .filter(|(stripe, _)| {
+                // Buckets beyond the end of the block number space cannot hold any keys
+                u32::try_from(*stripe as u64 * stripe_size).map_or(false, |blkno| {
+                    self.shard_for_relation_block(rel, blkno) == self.number
+                })
+            })
+            .map(|(_, keys)| keys)
+            .sum();
+        local as f64 / total as f64
+    }
+
+    /// How many blocks lie between `key` and the start of the next stripe, where ownership
+    /// may pass to another shard.  A key at the start of a stripe returns `stripe_size`,
+    /// and the last key in a stripe returns 1.
+    pub fn distance_to_stripe_boundary(&self, key: &Key) -> u64 {
+        let stripe_size = self.stripe_size.0 as u64;
+        stripe_size - (key.field6 as u64 % stripe_size)
+    }
+
+    /// Split `range` into the sub-ranges that lie within a single stripe, in key order.  Each
+    /// sub-range is owned by a single shard.
+    ///
+    /// This walks the range one stripe at a time, so is intended for ranges within a
+    /// relation rather than arbitrarily large parts of the keyspace.
+    fn stripe_segments(&self, range: Range<Key>) -> impl Iterator<Item = Range<Key>> + '_ {
+        let mut cursor = range.start;
+        std::iter::from_fn(move || {
+            if cursor >= range.end {
+                return None;
+            }
+
+            // Do not let a stripe carry over into the next fork/relation: the last stripe
+            // in a relation may be truncated by the end of the block number space.
+            let distance = self
+                .distance_to_stripe_boundary(&cursor)
+                .min((1u64 << 32) - cursor.field6 as u64);
+            let end = std::cmp::min(cursor.add(distance as u32), range.end);
+            let segment = cursor..end;
+            cursor = end;
+            Some(segment)
+        })
+    }
+
+    /// Whether this shard owns more than half of the stripes in `range`, e.g. to decide whether
+    /// it is worth serving a read of the range locally.
+    pub fn owns_majority_of_range(&self, range: Range<Key>) -> bool {
+        if self.count < ShardCount(2) {
+            return true;
+        }
+
+        let (mut owned, mut total) = (0, 0);
+        for segment in self.stripe_segments(range) {
+            total += 1;
+            if self.is_key_local(&segment.start) {
+                owned += 1;
+            }
+        }
+        owned * 2 > total
+    }
+
+    /// The stripe boundaries within `range`, excluding its start: the owning shard may
+    /// change at each of these keys, so reads of the range must be split there.  Neighbouring
+    /// stripes may happen to land on the same shard, so not every boundary is an ownership
+    /// change.  Empty for an unsharded tenant, whose reads never need splitting.
+    pub fn stripe_boundaries_in(&self, range: Range<Key>) -> Vec<Key> {
+        if self.count < ShardCount(2) {
+            return Vec::new();
+        }
+
+        self.stripe_segments(range)
+            .skip(1)
+            .map(|segment| segment.start)
+            .collect()
+    }
+
+    /// The shard that owns every key in `range`, if there is one: e.g. because the range lies
+    /// within one stripe.  Always shard zero for an unsharded or single-shard tenant.
+    pub fn range_is_single_shard(&self, range: Range<Key>) -> Option<ShardNumber> {
+        if self.count < ShardCount(2) {
+            return Some(ShardNumber(0));
+        }
+
+        let mut owners = self
+            .stripe_segments(range)
+            .map(|segment| self.get_shard_number(&segment.start));
+        let first = owners.next()?;
+        owners.all(|owner| owner == first).then_some(first)
+    }
+
+    /// Up to `max_samples` keys from `range` that this shard owns, each from a different
+    /// stripe: representative point queries for verifying this shard's data.
+    pub fn sample_owned_keys(&self, range: Range<Key>, max_samples: usize) -> Vec<Key> {
+        self.stripe_segments(range)
+            .map(|segment| segment.start)
+            .filter(|key| self.is_key_local(key))
+            .take(max_samples)
+            .collect()
+    }
+
+    /// Split `range` into contiguous sub-ranges owned by this shard, for planning batched
+    /// reads.  Adjacent stripes owned by this shard are merged into one sub-range.
+    pub fn batch_boundaries(&self, range: Range<Key>) -> Vec<(ShardNumber, Range<Key>)> {
+        let mut batches: Vec<(ShardNumber, Range<Key>)> = Vec::new();
+        for segment in self.stripe_segments(range) {
+            if !self.is_key_local(&segment.start) {
+                continue;
+            }
+
+            match batches.last_mut() {
+                Some((_, last)) if last.end == segment.start => last.end = segment.end,
+                _ => batches.push((self.number, segment)),
+            }
+        }
+        batches
+    }
+
+    /// Whether changing only the stripe size to `new_stripe` would move any of `sample_keys`
+    /// to a different shard.  Stripe size is part of the key->shard mapping, so in general
+    /// changing it requires data movement even though the shard count stays the same.
+    pub fn stripe_change_moves_data(
+        &self,
+        new_stripe: ShardStripeSize,
+        sample_keys: &[Key],
+    ) -> Result<bool, ShardConfigError> {
+        Ok(self.stripe_change_moved_count(new_stripe, sample_keys)? > 0)
+    }
+
+    /// Like [`Self::stripe_change_moves_data`], but counts how many of `sample_keys` would move.
+    pub fn stripe_change_moved_count(
+        &self,
+        new_stripe: ShardStripeSize,
+        sample_keys: &[Key],
+    ) -> Result<usize, ShardConfigError> {
+        let new_stripe = ShardStripeSize::new(new_stripe.0)?;
+        Ok(sample_keys
+            .iter()
+            .filter(|key| {
+                self.get_shard_number(key) != key_to_shard_number(self.count, new_stripe, key)
+            })
+            .count())
+    }
+}
+
+/// Provide the same result as the function in postgres `hash_combine_uint32`
+fn hash_combine(mut a: u32, mut b: u32) -> u32 {
+    b = b.wrapping_add(0x9e3779b9);
+    b = b.wrapping_add(a << 6);
+    b = b.wrapping_add(a >> 2);
+
+    a ^= b;
+    a
+}
+
+/// Provide the same result as the function in postgres `murmurhash32`
+fn murmurhash32(mut h: u32) -> u32 {
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85ebca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2ae35);
+    h ^= h >> 16;
+    h
+}
+
+/// Select the shard for a Key.  Contiguous runs of `stripe_size` blocks in the same relation
+/// stay together on one shard, while the stripes themselves are distributed pseudo-randomly.
+///
+/// The stripe size must be nonzero: callers take it from a validated [`ShardIdentity`], or
+/// check it with [`ShardStripeSize::new`].
+fn key_to_shard_number(count: ShardCount, stripe_size: ShardStripeSize, key: &Key) -> ShardNumber {
+    // Fast path for un-sharded tenants, and global keys that every tenant keeps on shard zero
+    if count < ShardCount(2) || ShardIdentity::is_global_key(key) {
+        return ShardNumber(0);
+    }
+
+    // relNode
+    let mut hash = murmurhash32(key.field4);
+    // blockNum/stripe size
+    hash = hash_combine(hash, murmurhash32(key.field6 / stripe_size.0));
+
+    if count.is_power_of_two() {
+        ShardNumber((hash & (count.0 as u32 - 1)) as u8)
+    } else {
+        ShardNumber((hash % count.0 as u32) as u8)
+    }
+}
+
+/// Pack the set of `present` shards of a tenant with `count` shards into a bitmap, one bit
+/// per shard number, least significant bit first.  Numbers beyond `count` are ignored.
+pub fn shards_present_bitmap(present: &[ShardNumber], count: ShardCount) -> Vec<u8> {
+    let mut bitmap = vec![0u8; (count.0.max(1) as usize + 7) / 8];
+    for number in present.iter().filter(|n| n.0 < count.0.max(1)) {
+        bitmap[number.0 as usize / 8] |= 1 << (number.0 % 8);
+    }
+    bitmap
+}
+
+/// Inverse of [`shards_present_bitmap`]
+pub fn shards_from_bitmap(bytes: &[u8], count: ShardCount) -> Vec<ShardNumber> {
+    count
+        .shard_numbers()
+        .filter(|n| {
+            bytes
+                .get(n.0 as usize / 8)
+                .map_or(false, |b| b & (1 << (n.0 % 8)) != 0)
+        })
+        .collect()
+}
+
+/// Which shard of `tenant_id` holds `key`, given the tenant's layout as described by any
+/// of its shards' `identity`.
+pub fn route_key(tenant_id: TenantId, key: &Key, identity: &ShardIdentity) -> TenantShardId {
+    identity.resolve(tenant_id, key)
+}
+
+/// The shards that may hold `key` while a tenant moves from the `old` layout to the `new`
+/// one (e.g. during a split): the owner under the old layout, followed by the owner under
+/// the new layout.  Point lookups during the transition must consult both.
+///
+/// The two numbers belong to different shard counts, so they name different shards even
+/// when they are equal: shard 1 of 2 is not shard 1 of 4.  Only when `old` and `new`
+/// describe the same layout is there a single shard to consult, and a single entry.
+pub fn lookup_shards(key: &Key, old: &ShardIdentity, new: &ShardIdentity) -> Vec<ShardNumber> {
+    let same_layout = old.layout == new.layout
+        && old.count.is_noop_split(new.count)
+        && old.stripe_size == new.stripe_size;
+    if same_layout {
+        vec![new.get_shard_number(key)]
+    } else {
+        vec![old.get_shard_number(key), new.get_shard_number(key)]
+    }
+}
+
+/// A compact token describing a change of shard count, for audit logs: for example
+/// a split from 4 to 8 shards is written as `04->08`.
+pub fn count_transition_token(old: ShardCount, new: ShardCount) -> String {
+    format!("{:02x}->{:02x}", old.0, new.0)
+}
+
+/// Inverse of [`count_transition_token`]
+pub fn parse_count_transition(s: &str) -> Result<(ShardCount, ShardCount), hex::FromHexError> {
+    // Expect format: 1 byte old count, '->', 1 byte new count
+    let bytes = s.as_bytes();
+    if bytes.len() != 6 || &bytes[2..4] != b"->" {
+        return Err(hex::FromHexError::InvalidStringLength);
+    }
+
+    let mut old = [0u8; 1];
+    let mut new = [0u8; 1];
+    hex::decode_to_slice(&bytes[0..2], &mut old)?;
+    hex::decode_to_slice(&bytes[4..6], &mut new)?;
+    Ok((ShardCount(old[0]), ShardCount(new[0])))
+}
+
+/// The identities of every shard in a tenant with `count` shards.  Legacy unsharded tenants
+/// have no shard count: use [`ShardIdentity::unsharded`] for those.
+pub fn shard_identities(
+    count: ShardCount,
+    stripe_size: ShardStripeSize,
+) -> Result<impl Iterator<Item = ShardIdentity>, ShardConfigError> {
+    // If shard zero is valid, so is every other number below the count
+    let first = ShardIdentity::new(ShardNumber(0), count, stripe_size)?;
+    Ok((0..count.0).map(move |number| ShardIdentity {
+        number: ShardNumber(number),
+        ..first
+    }))
+}
+
+/// Every shard of `tenant_id` with `count` shards, as its id paired with its identity
+pub fn tenant_shard_identities(
+    tenant_id: TenantId,
+    count: ShardCount,
+    stripe_size: ShardStripeSize,
+) -> Result<Vec<(TenantShardId, ShardIdentity)>, ShardConfigError> {
+    Ok(shard_identities(count, stripe_size)?
+        .map(|identity| {
+            let id = TenantShardId {
+                tenant_id,
+                shard_number: identity.number,
+                shard_count: identity.count,
+            };
+            (id, identity)
+        })
+        .collect())
+}
+
+/// Debug guard against key mapping bugs: check that exactly one shard of a tenant with
+/// `count` shards claims `key`, and return it.
+pub fn assert_single_owner(
+    count: ShardCount,
+    stripe_size: ShardStripeSize,
+    key: &Key,
+) -> Result<ShardNumber, &'static str> {
+    let identities: Vec<ShardIdentity> = if count.is_unsharded() {
+        vec![ShardIdentity {
+            stripe_size: ShardStripeSize::new(stripe_size.0)
+                .map_err(|_| "invalid shard configuration")?,
+            ..ShardIdentity::unsharded()
+        }]
+    } else {
+        shard_identities(count, stripe_size)
+            .map_err(|_| "invalid shard configuration")?
+            .collect()
+    };
+
+    let mut owners = identities
+        .iter()
+        .filter(|identity| identity.is_key_local(key));
+    match (owners.next(), owners.next()) {
+        (Some(owner), None) => Ok(owner.number),
+        (None, _) => Err("key is not owned by any shard"),
+        (Some(_), Some(_)) => Err("key is owned by more than one shard"),
+    }
+}
+
+/// Validate a staged split, from a tenant with `start` shards through each of the counts in
+/// `plan` in turn: every step must split each shard into a whole number of children, so each
+/// count must be a strictly larger multiple of the previous one.  ShardCount's range already
+/// bounds the final count by [`ShardCount::MAX`].
+pub fn validate_split_plan(start: ShardCount, plan: &[ShardCount]) -> Result<(), ShardConfigError> {
+    // A legacy unsharded tenant has a single shard
+    let mut current = ShardCount(start.0.max(1));
+    for step in plan {
+        if !step.is_split_of(current) {
+            return Err(ShardConfigError::InvalidCount);
+        }
+        current = *step;
+    }
+    Ok(())
+}
+
+/// Roughly how many of a parent shard's `parent_layers` layer files would have to move to
+/// other shards when splitting from `old_count` to `new_count` shards: each parent keeps
+/// `old_count / new_count` of its data, and the rest moves to its new siblings.
+pub fn layers_moved_estimate(
+    parent_layers: usize,
+    old_count: ShardCount,
+    new_count: ShardCount,
+) -> Result<usize, ShardConfigError> {
+    validate_split_plan(old_count, &[new_count])?;
+    // A legacy unsharded tenant has a single shard
+    let old = old_count.0.max(1) as u128;
+    let new = new_count.0 as u128;
+    // Multiply in u128 so that large layer counts cannot overflow: the quotient is at most
+    // parent_layers, so it always fits back in a usize.
+    let moved = parent_layers as u128 * (new - old) / new;
+    Ok(moved as usize)
+}
+
+/// The shard numbers that splitting from `old` to `new` shards creates: those in `new`
+/// that did not exist in `old`.  `new` must be a larger multiple of `old`.
+pub fn new_shard_numbers(
+    old: ShardCount,
+    new: ShardCount,
+) -> Result<Vec<ShardNumber>, ShardConfigError> {
+    validate_split_plan(old, &[new])?;
+    Ok((old.0.max(1)..new.0).map(ShardNumber).collect())
+}
+
+/// Whether any of `sample_keys` is owned by both `a` and `b`, which may have different
+/// shard counts.  This is true for an ancestor/descendant pair (e.g. shard 0 of 2 and
+/// shard 2 of 4), and false for distinct shards of the same layout.
+pub fn shared_ownership_exists(a: &ShardIdentity, b: &ShardIdentity, sample_keys: &[Key]) -> bool {
+    sample_keys
+        .iter()
+        .any(|key| a.is_key_local(key) && b.is_key_local(key))
+}
+
+/// A one-line, human-readable description of changing a tenant's shard count, for audit logs.
+pub fn reshard_log_line(
+    tenant_id: TenantId,
+    from: ShardCount,
+    to: ShardCount,
+    stripe: ShardStripeSize,
+) -> String {
+    let verb = if from.is_noop_split(to) {
+        "resharding (no-op)"
+    } else if to > from {
+        "splitting"
+    } else {
+        "merging"
+    };
+    format!(
+        "tenant {tenant_id}: {verb} {} -> {} shards at stripe {}",
+        from.0, to.0, stripe.0
+    )
+}
+
+/// The fraction of `sample_keys` whose owner under the larger of two shard counts is a
+/// descendant of its owner under the smaller count, i.e. whose data stays within the
+/// "same relative" shard when moving between the two layouts.  When one count is a multiple
+/// of the other this is always 1.0.
+pub fn ownership_overlap(
+    count_a: ShardCount,
+    count_b: ShardCount,
+    stripe_size: ShardStripeSize,
+    sample_keys: &[Key],
+) -> Result<f64, ShardConfigError> {
+    let stripe_size = ShardStripeSize::new(stripe_size.0)?;
+    if sample_keys.is_empty() {
+        return Ok(1.0);
+    }
+
+    let (smaller, larger) = if count_a <= count_b {
+        (count_a, count_b)
+    } else {
+        (count_b, count_a)
+    };
+    let stays = sample_keys
+        .iter()
+        .filter(|key| {
+            let coarse = key_to_shard_number(smaller, stripe_size, key);
+            let fine = key_to_shard_number(larger, stripe_size, key);
+            fine.0 % smaller.0.max(1) == coarse.0
+        })
+        .count();
+    Ok(stays as f64 / sample_keys.len() as f64)
+}
+
+/// Whether shards `a` and `b` could both hold one of `sample_keys` at the same time, e.g.
+/// a parent and a child shard while a split is in progress.  Shards of different tenants
+/// never share keys.
+pub fn concurrent_ownership(
+    a: &TenantShardId,
+    b: &TenantShardId,
+    sample_keys: &[Key],
+    a_stripe: ShardStripeSize,
+    b_stripe: ShardStripeSize,
+) -> bool {
+    let identity = |id: &TenantShardId, stripe_size: ShardStripeSize| {
+        if id.shard_count.is_unsharded() {
+            Ok(ShardIdentity {
+                stripe_size: ShardStripeSize::new(stripe_size.0)?,
+                ..ShardIdentity::unsharded()
+            })
+        } else {
+            ShardIdentity::new(id.shard_number, id.shard_count, stripe_size)
+        }
+    };
+
+    if a.tenant_id != b.tenant_id {
+        return false;
+    }
+    match (identity(a, a_stripe), identity(b, b_stripe)) {
+        (Ok(a), Ok(b)) => shared_ownership_exists(&a, &b, sample_keys),
+        _ => false,
+    }
+}
+
+/// The number of stripes needed to cover `keyspace`, treating it as a contiguous run of
+/// blocks.  This is an upper bound on the number of distinct placement decisions made
+/// for the keys in the range, useful for planning how work will spread across shards.
+///
+/// Blocks are counted in block number (field6) space, as routing does: the other fields
+/// select a relation fork, each of which has 2^32 blocks.  Saturates at `u64::MAX`.  Returns
+/// None for a zero stripe size.
+pub fn total_stripes(keyspace: Range<Key>, stripe_size: ShardStripeSize) -> Option<u64> {
+    if stripe_size.0 == 0 {
+        return None;
+    }
+    if keyspace.end <= keyspace.start {
+        return Some(0);
+    }
+
+    let fork = |key: &Key| {
+        ((key.field1 as u128) << 104)
+            | ((key.field2 as u128) << 72)
+            | ((key.field3 as u128) << 40)
+            | ((key.field4 as u128) << 8)
+            | key.field5 as u128
+    };
+    // The end sorts after the start, so either its fork does too, or they share a fork
+    // and the end has the larger block number.
+    let blocks = (fork(&keyspace.end) - fork(&keyspace.start))
+        .checked_mul(1 << 32)
+        .and_then(|blocks| blocks.checked_add(keyspace.end.field6 as u128))
+        .map_or(u128::MAX, |blocks| blocks - keyspace.start.field6 as u128);
+    let stripes = blocks.div_ceil(stripe_size.0 as u128);
+    Some(u64::try_from(stripes).unwrap_or(u64::MAX))
+}
+
+/// A ShardIdentity's key->shard mapping, prepared for routing a large number of keys.
+///
+/// Each call to [`ShardIdentity::get_shard_number`] performs an integer division by the
+/// stripe size and a modulo by the shard count.  These are among the slowest arithmetic
+/// instructions, and on hot paths (e.g. ingesting WAL for a single shard) they are all
+/// applied with the same divisors.  The router computes the divisors' reciprocals once at
+/// construction, so that routing a key uses only multiplications (Lemire et al, "Faster
+/// Remainder by Direct Computation").
+#[derive(Clone, Copy, Debug)]
+pub struct ShardRouter {
+    count: ShardCount,
+    stripe_size: ShardStripeSize,
+    stripe_reciprocal: u64,
+    count_reciprocal: u64,
+}
+
+impl ShardRouter {
+    pub fn new(identity: &ShardIdentity) -> Result<Self, ShardConfigError> {
+        if identity.is_unsharded() {
+            if identity.stripe_size.0 == 0 {
+                return Err(ShardConfigError::InvalidStripeSize);
+            }
+        } else {
+            ShardIdentity::new(identity.number, identity.count, identity.stripe_size)?;
+        }
+
+        // For a divisor of 1 these wrap to zero: route() special-cases that stripe size, and
+        // a count of 1 never reaches the modulo.
+        let reciprocal = |d: u32| (u64::MAX / d as u64).wrapping_add(1);
+
+        Ok(Self {
+            count: identity.count,
+            stripe_size: identity.stripe_size,
+            stripe_reciprocal: reciprocal(identity.stripe_size.0),
+            count_reciprocal: reciprocal(identity.count.0.max(1) as u32),
+        })
+    }
+
+    /// Equivalent to [`ShardIdentity::get_shard_number`] for the identity this router
+    /// was built from.
+    pub fn route(&self, key: &Key) -> ShardNumber {
+        if self.count < ShardCount(2) || ShardIdentity::is_global_key(key) {
+            return ShardNumber(0);
+        }
+
+        let stripe = if self.stripe_size.0 == 1 {
+            key.field6
+        } else {
+            ((self.stripe_reciprocal as u128 * key.field6 as u128) >> 64) as u32
+        };
+
+        let hash = hash_combine(murmurhash32(key.field4), murmurhash32(stripe));
+
+        let lowbits = self.count_reciprocal.wrapping_mul(hash as u64);
+        ShardNumber(((lowbits as u128 * self.count.0 as u128) >> 64) as u8)
+    }
+}
+
+impl Serialize for ShardIndex {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            // Binary encoding is not used in index_part.json, but is included in anticipation of
+            // switching various structures (e.g. inter-process communication, remote metadata) to more
+            // compact binary encodings in future.
+            let mut packed: [u8; 2] = [0; 2];
+            packed[0] = self.shard_number.0;
+            packed[1] = self.shard_count.0;
+            packed.serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ShardIndex {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct IdVisitor {
+            is_human_readable_deserializer: bool,
+        }
+
+        impl<'de> serde::de::Visitor<'de> for IdVisitor {
+            type Value = ShardIndex;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                if self.is_human_readable_deserializer {
+                    formatter.write_str("value in form of hex string")
+                } else {
+                    formatter.write_str("value in form of integer array([u8; 2])")
+                }
+            }
+
+            fn visit_seq<A>(self, seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: serde::de::SeqAccess<'de>,
+            {
+                let s = serde::de::value::SeqAccessDeserializer::new(seq);
+                let id: [u8; 2] = Deserialize::deserialize(s)?;
+                Ok(ShardIndex::from(id))
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                ShardIndex::from_str(v).map_err(E::custom)
+            }
+        }
+
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(IdVisitor {
+                is_human_readable_deserializer: true,
+            })
+        } else {
+            deserializer.deserialize_tuple(
+                2,
+                IdVisitor {
                     is_human_readable_deserializer: false,
                 },
             )
         }
     }
-}
+}
+
+impl Serialize for WideShardIndex {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            // Length-prefixed, so that decoders can tell V1 from V2
+            serializer.serialize_bytes(&self.to_bytes())
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for WideShardIndex {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct IdVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for IdVisitor {
+            type Value = WideShardIndex;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("value in form of hex string or 2 or 4 bytes")
+            }
+
+            fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                WideShardIndex::from_bytes(v).map_err(E::custom)
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                WideShardIndex::from_str(v).map_err(E::custom)
+            }
+        }
+
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(IdVisitor)
+        } else {
+            deserializer.deserialize_bytes(IdVisitor)
+        }
+    }
+}
+
+/// Helpers for tests and benchmarks of key distribution
+#[cfg(any(test, feature = "testing"))]
+pub mod test_util {
+    use super::{key_to_shard_number, ShardCount, ShardStripeSize};
+    use crate::key::Key;
+
+    /// `n` relation block keys, pseudo-randomly spread over many relations and stripes.  The
+    /// same `seed` always produces the same keys.
+    pub fn synthetic_keys(n: usize, seed: u64) -> Vec<Key> {
+        // splitmix64
+        let mut state = seed;
+        let mut next = move || {
+            state = state.wrapping_add(0x9e3779b97f4a7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+            z ^ (z >> 31)
+        };
+
+        (0..n)
+            .map(|_| {
+                let r = next();
+                Key {
+                    field1: 0x00,
+                    field2: 1663,
+                    field3: 5,
+                    // Postgres never uses relfilenode 0
+                    field4: 1 + (r >> 32) as u32 % 100_000,
+                    field5: 0,
+                    // Stay clear of the relation size key at 0xffffffff
+                    field6: r as u32 >> 1,
+                }
+            })
+            .collect()
+    }
+
+    /// How many of `keys` each shard of a tenant with `count` shards would own
+    pub fn balance_report(
+        count: ShardCount,
+        stripe_size: ShardStripeSize,
+        keys: &[Key],
+    ) -> Vec<usize> {
+        let mut report = vec![0; count.0.max(1) as usize];
+        for key in keys {
+            report[key_to_shard_number(count, stripe_size, key).0 as usize] += 1;
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bincode;
+    use utils::{id::TenantId, Hex};
+
+    use super::*;
+
+    const EXAMPLE_TENANT_ID: &str = "1f359dd625e519a1a4e8d7509690f6fc";
+
+    /// A key for block `blkno` of a relation in the default tablespace
+    fn rel_block_key(relnode: u32, blkno: u32) -> Key {
+        Key {
+            field1: 0x00,
+            field2: 1663,
+            field3: 5,
+            field4: relnode,
+            field5: 0,
+            field6: blkno,
+        }
+    }
+
+    #[test]
+    fn tenant_shard_id_string() -> Result<(), hex::FromHexError> {
+        let example = TenantShardId {
+            tenant_id: TenantId::from_str(EXAMPLE_TENANT_ID).unwrap(),
+            shard_count: ShardCount(10),
+            shard_number: ShardNumber(7),
+        };
+
+        let encoded = format!("{example}");
+
+        let expected = format!("{EXAMPLE_TENANT_ID}-070a");
+        assert_eq!(&encoded, &expected);
+
+        let decoded = TenantShardId::from_str(&encoded)?;
+
+        assert_eq!(example, decoded);
+
+        Ok(())
+    }
+
+    #[test]
+    fn tenant_shard_id_binary() -> Result<(), hex::FromHexError> {
+        let example = TenantShardId {
+            tenant_id: TenantId::from_str(EXAMPLE_TENANT_ID).unwrap(),
+            shard_count: ShardCount(10),
+            shard_number: ShardNumber(7),
+        };
+
+        let encoded = bincode::serialize(&example).unwrap();
+        let expected: [u8; 18] = [
+            0x1f, 0x35, 0x9d, 0xd6, 0x25, 0xe5, 0x19, 0xa1, 0xa4, 0xe8, 0xd7, 0x50, 0x96, 0x90,
+            0xf6, 0xfc, 0x07, 0x0a,
+        ];
+        assert_eq!(Hex(&encoded), Hex(&expected));
+
+        let decoded = bincode::deserialize(&encoded).unwrap();
+
+        assert_eq!(example, decoded);
+
+        Ok(())
+    }
+
+    #[test]
+    fn tenant_shard_id_backward_compat() -> Result<(), hex::FromHexError> {
+        // Test that TenantShardId can decode a TenantId in human
+        // readable form
+        let example = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+        let encoded = format!("{example}");
+
+        assert_eq!(&encoded, EXAMPLE_TENANT_ID);
+
+        let decoded = TenantShardId::from_str(&encoded)?;
+
+        assert_eq!(example, decoded.tenant_id);
+        assert_eq!(decoded.shard_count, ShardCount(0));
+        assert_eq!(decoded.shard_number, ShardNumber(0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn tenant_shard_id_forward_compat() -> Result<(), hex::FromHexError> {
+        // Test that a legacy TenantShardId encodes into a form that
+        // can be decoded as TenantId
+        let example_tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+        let example = TenantShardId::unsharded(example_tenant_id);
+        let encoded = format!("{example}");
+
+        assert_eq!(&encoded, EXAMPLE_TENANT_ID);
+
+        let decoded = TenantId::from_str(&encoded)?;
+
+        assert_eq!(example_tenant_id, decoded);
+
+        Ok(())
+    }
+
+    #[test]
+    fn tenant_shard_id_legacy_binary() -> Result<(), hex::FromHexError> {
+        // Unlike in human readable encoding, binary encoding does not
+        // do any special handling of legacy unsharded TenantIds: this test
+        // is equivalent to the main test for binary encoding, just verifying
+        // that the same behavior applies when we have used `unsharded()` to
+        // construct a TenantShardId.
+        let example = TenantShardId::unsharded(TenantId::from_str(EXAMPLE_TENANT_ID).unwrap());
+        let encoded = bincode::serialize(&example).unwrap();
+
+        let expected: [u8; 18] = [
+            0x1f, 0x35, 0x9d, 0xd6, 0x25, 0xe5, 0x19, 0xa1, 0xa4, 0xe8, 0xd7, 0x50, 0x96, 0x90,
+            0xf6, 0xfc, 0x00, 0x00,
+        ];
+        assert_eq!(Hex(&encoded), Hex(&expected));
+
+        let decoded = bincode::deserialize::<TenantShardId>(&encoded).unwrap();
+        assert_eq!(example, decoded);
+
+        Ok(())
+    }
+
+    #[test]
+    fn shard_identity_validation() -> Result<(), ShardConfigError> {
+        // Happy cases
+        ShardIdentity::new(ShardNumber(0), ShardCount(1), DEFAULT_STRIPE_SIZE)?;
+        ShardIdentity::new(ShardNumber(0), ShardCount(1), ShardStripeSize(1))?;
+        ShardIdentity::new(ShardNumber(254), ShardCount(255), ShardStripeSize(1))?;
+
+        assert_eq!(
+            ShardIdentity::new(ShardNumber(0), ShardCount(0), DEFAULT_STRIPE_SIZE),
+            Err(ShardConfigError::InvalidCount)
+        );
+        assert_eq!(
+            ShardIdentity::new(ShardNumber(10), ShardCount(10), DEFAULT_STRIPE_SIZE),
+            Err(ShardConfigError::InvalidNumber)
+        );
+        assert_eq!(
+            ShardIdentity::new(ShardNumber(11), ShardCount(10), DEFAULT_STRIPE_SIZE),
+            Err(ShardConfigError::InvalidNumber)
+        );
+        assert_eq!(
+            ShardIdentity::new(ShardNumber(255), ShardCount(255), DEFAULT_STRIPE_SIZE),
+            Err(ShardConfigError::InvalidNumber)
+        );
+        assert_eq!(
+            ShardIdentity::new(ShardNumber(0), ShardCount(1), ShardStripeSize(0)),
+            Err(ShardConfigError::InvalidStripeSize)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn shard_router_matches_identity() -> Result<(), ShardConfigError> {
+        let stripe_sizes = [
+            ShardStripeSize(1),
+            ShardStripeSize(3),
+            ShardStripeSize(1000),
+            DEFAULT_STRIPE_SIZE,
+        ];
+        let mut identities = vec![ShardIdentity::unsharded()];
+        for count in [1, 2, 3, 4, 7, 8, 255] {
+            for stripe_size in stripe_sizes {
+                identities.push(ShardIdentity::new(
+                    ShardNumber(0),
+                    ShardCount(count),
+                    stripe_size,
+                )?);
+            }
+        }
+
+        for identity in identities {
+            let router = ShardRouter::new(&identity)?;
+            for relnode in [1, 1259, 16384, 0xdeadbeef] {
+                for blkno in (0..100_000).step_by(97).chain([u32::MAX - 1, u32::MAX]) {
+                    let key = rel_block_key(relnode, blkno);
+                    assert_eq!(
+                        router.route(&key),
+                        identity.get_shard_number(&key),
+                        "{identity:?} {key}"
+                    );
+                }
+            }
+        }
+
+        assert_eq!(
+            ShardRouter::new(&ShardIdentity {
+                stripe_size: ShardStripeSize(0),
+                ..ShardIdentity::unsharded()
+            })
+            .unwrap_err(),
+            ShardConfigError::InvalidStripeSize
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn shard_index_human_encoding() -> Result<(), hex::FromHexError> {
+        let example = ShardIndex {
+            shard_number: ShardNumber(13),
+            shard_count: ShardCount(17),
+        };
+        let expected: String = "0d11".to_string();
+        let encoded = format!("{example}");
+        assert_eq!(&encoded, &expected);
+
+        let decoded = ShardIndex::from_str(&encoded)?;
+        assert_eq!(example, decoded);
+        Ok(())
+    }
+
+    #[test]
+    fn shard_index_binary_encoding() -> Result<(), hex::FromHexError> {
+        let example = ShardIndex {
+            shard_number: ShardNumber(13),
+            shard_count: ShardCount(17),
+        };
+        let expected: [u8; 2] = [0x0d, 0x11];
+
+        let encoded = bincode::serialize(&example).unwrap();
+        assert_eq!(Hex(&encoded), Hex(&expected));
+        let decoded = bincode::deserialize(&encoded).unwrap();
+        assert_eq!(example, decoded);
+
+        Ok(())
+    }
+
+    #[test]
+    fn tenant_shard_id_matches_identity() -> Result<(), ShardConfigError> {
+        let tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+        let legacy = TenantShardId::unsharded(tenant_id);
+        let single = ShardIdentity::new(ShardNumber(0), ShardCount(1), DEFAULT_STRIPE_SIZE)?;
+        let four = ShardIdentity::new(ShardNumber(0), ShardCount(4), DEFAULT_STRIPE_SIZE)?;
+
+        assert!(legacy.matches_identity(&single));
+        assert!(legacy.matches_identity(&ShardIdentity::unsharded()));
+        assert!(!legacy.matches_identity(&four));
+
+        let sharded = TenantShardId {
+            tenant_id,
+            shard_number: ShardNumber(0),
+            shard_count: ShardCount(4),
+        };
+        assert!(sharded.matches_identity(&four));
+        assert!(!sharded.matches_identity(&single));
+
+        Ok(())
+    }
+
+    #[test]
+    fn total_stripes_of_keyspace() {
+        let rel_block = |blkno| rel_block_key(16384, blkno);
+        let stripe_size = ShardStripeSize(8);
+
+        assert_eq!(
+            total_stripes(rel_block(0)..rel_block(0), stripe_size),
+            Some(0)
+        );
+        assert_eq!(
+            total_stripes(rel_block(0)..rel_block(1), stripe_size),
+            Some(1)
+        );
+        assert_eq!(
+            total_stripes(rel_block(0)..rel_block(80), stripe_size),
+            Some(10)
+        );
+        assert_eq!(
+            total_stripes(rel_block(0)..rel_block(81), stripe_size),
+            Some(11)
+        );
+        assert_eq!(
+            total_stripes(rel_block(80)..rel_block(0), stripe_size),
+            Some(0)
+        );
+
+        // Each relation fork has 2^32 blocks
+        let next_fork = Key {
+            field5: 1,
+            ..rel_block(16)
+        };
+        assert_eq!(
+            total_stripes(rel_block(u32::MAX - 7)..next_fork, stripe_size),
+            Some(3)
+        );
+
+        // Fields that Key::to_i128 cannot represent are fine
+        let wide = Key {
+            field2: 0x1_0000,
+            ..rel_block(0)
+        };
+        assert_eq!(
+            total_stripes(wide..Key { field6: 80, ..wide }, stripe_size),
+            Some(10)
+        );
+        assert_eq!(
+            total_stripes(Key::MIN..Key::MAX, stripe_size),
+            Some(u64::MAX)
+        );
+
+        assert_eq!(
+            total_stripes(rel_block(0)..rel_block(80), ShardStripeSize(0)),
+            None
+        );
+    }
+
+    #[test]
+    fn stripe_change_moves_data() -> Result<(), ShardConfigError> {
+        let identity = ShardIdentity::new(ShardNumber(0), ShardCount(4), ShardStripeSize(8))?;
+        let keys: Vec<Key> = (0..1024).map(|blkno| rel_block_key(16384, blkno)).collect();
+
+        assert!(!identity.stripe_change_moves_data(identity.stripe_size, &keys)?);
+        assert_eq!(
+            identity.stripe_change_moved_count(identity.stripe_size, &keys)?,
+            0
+        );
+
+        let doubled = ShardStripeSize(identity.stripe_size.0 * 2);
+        assert!(identity.stripe_change_moves_data(doubled, &keys)?);
+        let moved = identity.stripe_change_moved_count(doubled, &keys)?;
+        assert!(moved > 0 && moved < keys.len());
+
+        // Nothing moves in a tenant with only one shard
+        let single = ShardIdentity::new(ShardNumber(0), ShardCount(1), ShardStripeSize(8))?;
+        assert!(!single.stripe_change_moves_data(doubled, &keys)?);
+
+        // A zero stripe size is refused rather than dividing by zero
+        assert_eq!(
+            identity.stripe_change_moves_data(ShardStripeSize(0), &keys),
+            Err(ShardConfigError::InvalidStripeSize)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn shard_index_try_from_tenant_shard_id() {
+        let tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+
+        let valid = TenantShardId {
+            tenant_id,
+            shard_number: ShardNumber(3),
+            shard_count: ShardCount(8),
+        };
+        assert_eq!(
+            ShardIndex::try_from_tenant_shard_id(&valid),
+            Ok(ShardIndex::new(ShardNumber(3), ShardCount(8)))
+        );
+        assert_eq!(
+            ShardIndex::try_from_tenant_shard_id(&TenantShardId::unsharded(tenant_id)),
+            Ok(ShardIndex::unsharded())
+        );
+
+        let inconsistent = TenantShardId {
+            tenant_id,
+            shard_number: ShardNumber(7),
+            shard_count: ShardCount(7),
+        };
+        assert_eq!(
+            ShardIndex::try_from_tenant_shard_id(&inconsistent),
+            Err(ShardConfigError::InvalidNumber)
+        );
+    }
+
+    #[test]
+    fn lookup_shards_during_split() -> Result<(), ShardConfigError> {
+        let old = ShardIdentity::new(ShardNumber(0), ShardCount(2), ShardStripeSize(8))?;
+        let new = ShardIdentity::new(ShardNumber(0), ShardCount(4), ShardStripeSize(8))?;
+
+        for blkno in (0..256).step_by(8) {
+            let key = rel_block_key(16384, blkno);
+            let shards = lookup_shards(&key, &old, &new);
+            assert_eq!(
+                shards,
+                vec![old.get_shard_number(&key), new.get_shard_number(&key)]
+            );
+            // The child that takes over the key descends from the parent that held it
+            assert_eq!(shards[1].0 % old.count.0, shards[0].0);
+        }
+
+        // Equal numbers in the two layouts are still two different shards
+        let key = (0..256)
+            .map(|blkno| rel_block_key(16384, blkno))
+            .find(|key| old.get_shard_number(key) == new.get_shard_number(key))
+            .unwrap();
+        assert_eq!(lookup_shards(&key, &old, &new).len(), 2);
+
+        // Without a change of layout there is only one shard to ask
+        assert_eq!(
+            lookup_shards(&key, &new, &new),
+            vec![new.get_shard_number(&key)]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn count_transition_token_roundtrip() -> Result<(), hex::FromHexError> {
+        let split = count_transition_token(ShardCount(4), ShardCount(8));
+        assert_eq!(split, "04->08");
+        assert_eq!(
+            parse_count_transition(&split)?,
+            (ShardCount(4), ShardCount(8))
+        );
+
+        let merge = count_transition_token(ShardCount(32), ShardCount(16));
+        assert_eq!(merge, "20->10");
+        assert_eq!(
+            parse_count_transition(&merge)?,
+            (ShardCount(32), ShardCount(16))
+        );
+
+        assert!(parse_count_transition("04-08").is_err());
+        assert!(parse_count_transition("04=>08").is_err());
+        assert!(parse_count_transition("0x->08").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn distance_to_stripe_boundary() -> Result<(), ShardConfigError> {
+        let identity = ShardIdentity::new(ShardNumber(0), ShardCount(4), ShardStripeSize(8))?;
+
+        assert_eq!(
+            identity.distance_to_stripe_boundary(&rel_block_key(16384, 16)),
+            8
+        );
+        assert_eq!(
+            identity.distance_to_stripe_boundary(&rel_block_key(16384, 17)),
+            7
+        );
+        assert_eq!(
+            identity.distance_to_stripe_boundary(&rel_block_key(16384, 23)),
+            1
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn tenant_shard_id_local_dir_name() -> Result<(), hex::FromHexError> {
+        let tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+
+        let legacy = TenantShardId::unsharded(tenant_id);
+        assert_eq!(legacy.local_dir_name(), EXAMPLE_TENANT_ID);
+        assert_eq!(
+            TenantShardId::from_local_dir_name(&legacy.local_dir_name())?,
+            legacy
+        );
+
+        let sharded = TenantShardId {
+            tenant_id,
+            shard_number: ShardNumber(1),
+            shard_count: ShardCount(4),
+        };
+        assert_eq!(
+            sharded.local_dir_name(),
+            format!("{EXAMPLE_TENANT_ID}-0104")
+        );
+        assert_eq!(
+            TenantShardId::from_local_dir_name(&sharded.local_dir_name())?,
+            sharded
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn shared_ownership() -> Result<(), ShardConfigError> {
+        let keys: Vec<Key> = (0..1024).map(|blkno| rel_block_key(16384, blkno)).collect();
+        let stripe_size = ShardStripeSize(8);
+
+        let parent = ShardIdentity::new(ShardNumber(0), ShardCount(2), stripe_size)?;
+        let child = ShardIdentity::new(ShardNumber(2), ShardCount(4), stripe_size)?;
+        assert!(shared_ownership_exists(&parent, &child, &keys));
+        assert!(shared_ownership_exists(&child, &parent, &keys));
+
+        let sibling_a = ShardIdentity::new(ShardNumber(0), ShardCount(4), stripe_size)?;
+        let sibling_b = ShardIdentity::new(ShardNumber(1), ShardCount(4), stripe_size)?;
+        assert!(!shared_ownership_exists(&sibling_a, &sibling_b, &keys));
+
+        // Shard 1 of 2 is not an ancestor of shard 2 of 4
+        let other_parent = ShardIdentity::new(ShardNumber(1), ShardCount(2), stripe_size)?;
+        assert!(!shared_ownership_exists(&other_parent, &child, &keys));
+
+        Ok(())
+    }
+
+    #[test]
+    fn tenant_shard_id_position_in_tenant() {
+        let tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+        let shard = |number, count| TenantShardId {
+            tenant_id,
+            shard_number: ShardNumber(number),
+            shard_count: ShardCount(count),
+        };
+
+        assert_eq!(
+            TenantShardId::unsharded(tenant_id).position_in_tenant(),
+            0.0
+        );
+        assert_eq!(shard(0, 1).position_in_tenant(), 0.0);
+        assert_eq!(shard(0, 4).position_in_tenant(), 0.0);
+        assert_eq!(shard(2, 4).position_in_tenant(), 0.5);
+        assert_eq!(shard(3, 4).position_in_tenant(), 0.75);
+    }
+
+    #[test]
+    fn shard_counts_in_use() {
+        let mut map = BTreeMap::new();
+        for count in [1, 4, 8] {
+            let tenant_id = TenantId::generate();
+            for number in 0..count {
+                map.insert(
+                    TenantShardId {
+                        tenant_id,
+                        shard_number: ShardNumber(number),
+                        shard_count: ShardCount(count),
+                    },
+                    (),
+                );
+            }
+        }
+
+        assert_eq!(
+            counts_in_use(&map),
+            BTreeSet::from([ShardCount(1), ShardCount(4), ShardCount(8)])
+        );
+    }
+
+    #[test]
+    fn shard_number_decimal_padding() {
+        assert_eq!(ShardCount(0).max_decimal_width(), 1);
+        assert_eq!(ShardCount(1).max_decimal_width(), 1);
+        assert_eq!(ShardCount(10).max_decimal_width(), 1);
+        assert_eq!(ShardCount(11).max_decimal_width(), 2);
+        assert_eq!(ShardCount(100).max_decimal_width(), 2);
+        assert_eq!(ShardCount(101).max_decimal_width(), 3);
+
+        let width = ShardCount(10).max_decimal_width();
+        assert_eq!(ShardNumber(7).pad_decimal(width), "7");
+
+        let width = ShardCount(100).max_decimal_width();
+        assert_eq!(ShardNumber(7).pad_decimal(width), "07");
+        assert_eq!(ShardNumber(99).pad_decimal(width), "99");
+
+        assert_eq!(ShardNumber(7).pad_decimal(3), "007");
+    }
+
+    #[test]
+    fn tenant_shard_id_canonical_bytes() {
+        let mut bytes: [u8; 18] = [
+            0x1f, 0x35, 0x9d, 0xd6, 0x25, 0xe5, 0x19, 0xa1, 0xa4, 0xe8, 0xd7, 0x50, 0x96, 0x90,
+            0xf6, 0xfc, 0x06, 0x07,
+        ];
+        assert!(TenantShardId::from(bytes).canonical_bytes());
+
+        bytes[16] = 0x07;
+        assert!(!TenantShardId::from(bytes).canonical_bytes());
+
+        bytes[16] = 0x00;
+        bytes[17] = 0x00;
+        assert!(TenantShardId::from(bytes).canonical_bytes());
+    }
+
+    #[test]
+    fn shard_for_relation() -> Result<(), ShardConfigError> {
+        let rel = RelTag {
+            forknum: 0,
+            spcnode: 1663,
+            dbnode: 5,
+            relnode: 16384,
+        };
+        let mut hits = [0; 4];
+        for number in 0..4 {
+            let identity =
+                ShardIdentity::new(ShardNumber(number), ShardCount(4), ShardStripeSize(8))?;
+            assert_eq!(identity.shard_for_relation_metadata(), ShardNumber(0));
+
+            for blkno in 0..1024 {
+                let shard = identity.shard_for_relation_block(rel, blkno);
+                assert_eq!(
+                    shard,
+                    identity.get_shard_number(&rel_block_key(16384, blkno))
+                );
+                if number == 0 {
+                    hits[shard.0 as usize] += 1;
+                }
+            }
+        }
+        assert!(hits.iter().all(|h| *h > 0), "{hits:?}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn shard_identities_for_count() -> Result<(), ShardConfigError> {
+        let identities: Vec<_> = shard_identities(ShardCount(4), DEFAULT_STRIPE_SIZE)?.collect();
+        assert_eq!(identities.len(), 4);
+        for (i, identity) in identities.iter().enumerate() {
+            assert_eq!(identity.number, ShardNumber(i as u8));
+            assert_eq!(
+                ShardIdentity::new(identity.number, identity.count, identity.stripe_size)?,
+                *identity
+            );
+        }
+
+        assert_eq!(
+            shard_identities(ShardCount(0), DEFAULT_STRIPE_SIZE).err(),
+            Some(ShardConfigError::InvalidCount)
+        );
+        assert_eq!(
+            shard_identities(ShardCount(4), ShardStripeSize(0)).err(),
+            Some(ShardConfigError::InvalidStripeSize)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn tenant_shard_id_str_eq() {
+        let tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+        let sharded = TenantShardId {
+            tenant_id,
+            shard_number: ShardNumber(7),
+            shard_count: ShardCount(10),
+        };
+        let legacy = TenantShardId::unsharded(tenant_id);
+
+        assert!(sharded.str_eq(&format!("{EXAMPLE_TENANT_ID}-070a")));
+        assert!(!sharded.str_eq(EXAMPLE_TENANT_ID));
+        assert!(!sharded.str_eq(&format!("{EXAMPLE_TENANT_ID}-070b")));
+        assert!(!sharded.str_eq(&format!("{EXAMPLE_TENANT_ID}_070a")));
+        assert!(!sharded.str_eq("garbage"));
+
+        assert!(legacy.str_eq(EXAMPLE_TENANT_ID));
+        assert!(!legacy.str_eq(&format!("{EXAMPLE_TENANT_ID}-0000")));
+        assert!(!legacy.str_eq(&EXAMPLE_TENANT_ID.to_uppercase()));
+    }
+
+    #[test]
+    fn tenant_shard_id_select() {
+        let tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+        let count = ShardCount(4);
+        let numbers = |ids: Vec<TenantShardId>| {
+            ids.iter()
+                .map(|id| {
+                    assert_eq!(id.tenant_id, tenant_id);
+                    assert_eq!(id.shard_count, count);
+                    id.shard_number.0
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let all = TenantShardId::select(tenant_id, count, ShardSelector::All);
+        assert_eq!(numbers(all), vec![0, 1, 2, 3]);
+
+        let single = TenantShardId::select(tenant_id, count, ShardSelector::Single(ShardNumber(2)));
+        assert_eq!(numbers(single), vec![2]);
+
+        let range = TenantShardId::select(
+            tenant_id,
+            count,
+            ShardSelector::Range(ShardNumber(1), ShardNumber(3)),
+        );
+        assert_eq!(numbers(range), vec![1, 2, 3]);
+
+        // Selecting shards beyond the count yields nothing
+        let missing =
+            TenantShardId::select(tenant_id, count, ShardSelector::Single(ShardNumber(4)));
+        assert!(missing.is_empty());
+
+        assert_eq!(
+            TenantShardId::select(tenant_id, ShardCount(0), ShardSelector::All),
+            vec![TenantShardId::unsharded(tenant_id)]
+        );
+    }
+
+    #[test]
+    fn batch_boundaries() -> Result<(), ShardConfigError> {
+        let range = rel_block_key(16384, 3)..rel_block_key(16384, 1000);
+        let mut total = 0;
+        for number in 0..4 {
+            let identity =
+                ShardIdentity::new(ShardNumber(number), ShardCount(4), ShardStripeSize(8))?;
+            let batches = identity.batch_boundaries(range.clone());
+            assert!(!batches.is_empty());
+
+            let mut prev_end = range.start;
+            for (shard, batch) in batches {
+                assert_eq!(shard, identity.number);
+                assert!(batch.start >= prev_end && batch.start < batch.end);
+                assert!(batch.end <= range.end);
+                for blkno in batch.start.field6..batch.end.field6 {
+                    assert_eq!(
+                        identity.get_shard_number(&rel_block_key(16384, blkno)),
+                        identity.number
+                    );
+                }
+                total += batch.end.field6 - batch.start.field6;
+                prev_end = batch.end;
+            }
+        }
+
+        // Between them, the shards' batches cover the whole range
+        assert_eq!(total, 1000 - 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn stripe_alignment() -> Result<(), ShardConfigError> {
+        let two = ShardIdentity::new(ShardNumber(0), ShardCount(2), DEFAULT_STRIPE_SIZE)?;
+        let three = ShardIdentity::new(ShardNumber(0), ShardCount(3), DEFAULT_STRIPE_SIZE)?;
+        let four = ShardIdentity::new(ShardNumber(1), ShardCount(4), DEFAULT_STRIPE_SIZE)?;
+        let four_small = ShardIdentity::new(ShardNumber(1), ShardCount(4), ShardStripeSize(8))?;
+
+        assert!(two.stripe_aligned_with(&four));
+        assert!(four.stripe_aligned_with(&two));
+        assert!(!two.stripe_aligned_with(&three));
+        assert!(!two.stripe_aligned_with(&four_small));
+        assert!(ShardIdentity::unsharded().stripe_aligned_with(&three));
+
+        Ok(())
+    }
+
+    #[test]
+    fn shard_set_validation() -> Result<(), ShardConfigError> {
+        let identities: Vec<_> = shard_identities(ShardCount(4), DEFAULT_STRIPE_SIZE)?.collect();
+        assert_eq!(validate_shard_set(&identities), Ok(()));
+
+        let mut reversed = identities.clone();
+        reversed.reverse();
+        assert_eq!(validate_shard_set(&reversed), Ok(()));
+
+        assert_eq!(validate_shard_set(&[ShardIdentity::unsharded()]), Ok(()));
+        assert_eq!(validate_shard_set(&[]), Err(ShardSetError::Empty));
+        assert_eq!(
+            validate_shard_set(&identities[0..3]),
+            Err(ShardSetError::WrongSize {
+                expected: 4,
+                found: 3
+            })
+        );
+
+        let mut duplicated = identities.clone();
+        duplicated[3] = duplicated[2];
+        assert_eq!(
+            validate_shard_set(&duplicated),
+            Err(ShardSetError::Missing(ShardNumber(3)))
+        );
+
+        let mut mixed = identities.clone();
+        mixed[1].stripe_size = ShardStripeSize(8);
+        assert_eq!(validate_shard_set(&mixed), Err(ShardSetError::Inconsistent));
+
+        Ok(())
+    }
+
+    #[test]
+    fn shard_bundle_json() -> Result<(), ShardConfigError> {
+        let bundle = ShardBundle {
+            tenant_id: TenantId::from_str(EXAMPLE_TENANT_ID).unwrap(),
+            identities: shard_identities(ShardCount(4), DEFAULT_STRIPE_SIZE)?.collect(),
+        };
+
+        let encoded = serde_json::to_string(&bundle).unwrap();
+        assert!(encoded.contains(EXAMPLE_TENANT_ID));
+
+        let decoded: ShardBundle = serde_json::from_str(&encoded).unwrap();
+        assert_eq!(decoded, bundle);
+        assert_eq!(decoded.validate(), Ok(()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn shard_count_noop_split() {
+        assert!(ShardCount(4).is_noop_split(ShardCount(4)));
+        assert!(ShardCount(0).is_noop_split(ShardCount(1)));
+        assert!(ShardCount(1).is_noop_split(ShardCount(0)));
+        assert!(!ShardCount(4).is_noop_split(ShardCount(8)));
+        assert!(!ShardCount(0).is_noop_split(ShardCount(2)));
+    }
+
+    #[test]
+    fn reshard_log_lines() {
+        let tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+        assert_eq!(
+            reshard_log_line(tenant_id, ShardCount(4), ShardCount(8), DEFAULT_STRIPE_SIZE),
+            format!("tenant {EXAMPLE_TENANT_ID}: splitting 4 -> 8 shards at stripe 32768")
+        );
+        assert_eq!(
+            reshard_log_line(tenant_id, ShardCount(8), ShardCount(2), ShardStripeSize(8)),
+            format!("tenant {EXAMPLE_TENANT_ID}: merging 8 -> 2 shards at stripe 8")
+        );
+        assert_eq!(
+            reshard_log_line(tenant_id, ShardCount(0), ShardCount(1), ShardStripeSize(8)),
+            format!("tenant {EXAMPLE_TENANT_ID}: resharding (no-op) 0 -> 1 shards at stripe 8")
+        );
+    }
+
+    #[test]
+    fn ownership_overlap_between_counts() -> Result<(), ShardConfigError> {
+        let keys: Vec<Key> = (0..4096).map(|blkno| rel_block_key(16384, blkno)).collect();
+        let stripe_size = ShardStripeSize(8);
+
+        assert_eq!(
+            ownership_overlap(ShardCount(2), ShardCount(4), stripe_size, &keys)?,
+            1.0
+        );
+        assert_eq!(
+            ownership_overlap(ShardCount(8), ShardCount(2), stripe_size, &keys)?,
+            1.0
+        );
+        assert_eq!(
+            ownership_overlap(ShardCount(0), ShardCount(4), stripe_size, &keys)?,
+            1.0
+        );
+        assert!(ownership_overlap(ShardCount(2), ShardCount(3), stripe_size, &keys)? < 1.0);
+        assert_eq!(
+            ownership_overlap(ShardCount(2), ShardCount(4), ShardStripeSize(0), &keys),
+            Err(ShardConfigError::InvalidStripeSize)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn shard_index_array_encoding() {
+        let example = ShardIndex {
+            shard_number: ShardNumber(13),
+            shard_count: ShardCount(17),
+        };
+
+        let encoded = serde_json::to_string(&ShardIndexArray(example)).unwrap();
+        assert_eq!(encoded, "[13,17]");
+        let decoded: ShardIndexArray = serde_json::from_str(&encoded).unwrap();
+        assert_eq!(decoded.0, example);
+
+        // The plain type keeps its hex string form
+        assert_eq!(serde_json::to_string(&example).unwrap(), "\"0d11\"");
+
+        #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+        struct Holder {
+            #[serde(with = "shard_index_as_array")]
+            shard: ShardIndex,
+        }
+        let holder = Holder { shard: example };
+        let encoded = serde_json::to_string(&holder).unwrap();
+        assert_eq!(encoded, r#"{"shard":[13,17]}"#);
+        assert_eq!(serde_json::from_str::<Holder>(&encoded).unwrap(), holder);
+    }
+
+    #[test]
+    fn shard_count_rotated_order() {
+        let count = ShardCount(5);
+        for seed in 0..10 {
+            let order = count.rotated_order(seed);
+            assert_eq!(order[0], ShardNumber((seed % 5) as u8));
+            let mut sorted = order.clone();
+            sorted.sort();
+            assert_eq!(sorted, (0..5).map(ShardNumber).collect::<Vec<_>>());
+        }
+
+        assert_eq!(
+            count.rotated_order(2),
+            [2, 3, 4, 0, 1].map(ShardNumber).to_vec()
+        );
+        assert_ne!(count.rotated_order(1), count.rotated_order(3));
+        assert_eq!(ShardCount(0).rotated_order(7), vec![ShardNumber(0)]);
+    }
+
+    #[test]
+    fn tenant_shard_id_rate_limit_bucket() {
+        let tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+        let mut buckets = BTreeSet::new();
+        for number in 0..32 {
+            let id = TenantShardId {
+                tenant_id,
+                shard_number: ShardNumber(number),
+                shard_count: ShardCount(32),
+            };
+            let num_buckets = NonZeroU32::new(7).unwrap();
+            let bucket = id.rate_limit_bucket(num_buckets);
+            assert!(bucket < 7);
+            assert_eq!(bucket, id.rate_limit_bucket(num_buckets));
+            buckets.insert(bucket);
+        }
+        // Shards of one tenant are spread over more than one bucket
+        assert!(buckets.len() > 1);
+
+        assert_eq!(
+            TenantShardId::unsharded(tenant_id).rate_limit_bucket(NonZeroU32::MIN),
+            0
+        );
+    }
+
+    #[test]
+    fn shard_identity_from_index_like() -> Result<(), ShardConfigError> {
+        let reference = ShardIdentity::new(ShardNumber(0), ShardCount(4), ShardStripeSize(8))?;
+
+        let identity = ShardIdentity::from_index_like(
+            ShardIndex::new(ShardNumber(3), ShardCount(4)),
+            &reference,
+        )?;
+        assert_eq!(
+            identity,
+            ShardIdentity::new(ShardNumber(3), ShardCount(4), ShardStripeSize(8))?
+        );
+
+        assert_eq!(
+            ShardIdentity::from_index_like(
+                ShardIndex::new(ShardNumber(3), ShardCount(8)),
+                &reference
+            ),
+            Err(ShardConfigError::InvalidCount)
+        );
+
+        let legacy = ShardIdentity::unsharded();
+        assert_eq!(
+            ShardIdentity::from_index_like(ShardIndex::unsharded(), &legacy),
+            Ok(legacy)
+        );
+        assert_eq!(
+            ShardIdentity::from_index_like(ShardIndex::new(ShardNumber(1), ShardCount(0)), &legacy),
+            Err(ShardConfigError::InvalidCount)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn single_owner() {
+        for count in [0, 1, 2, 3, 8, 255] {
+            for blkno in (0..1024).step_by(7) {
+                let key = rel_block_key(16384, blkno);
+                let owner = assert_single_owner(ShardCount(count), ShardStripeSize(8), &key);
+                assert_eq!(
+                    owner,
+                    Ok(key_to_shard_number(
+                        ShardCount(count),
+                        ShardStripeSize(8),
+                        &key
+                    ))
+                );
+            }
+        }
+
+        assert!(
+            assert_single_owner(ShardCount(2), ShardStripeSize(0), &rel_block_key(1, 1)).is_err()
+        );
+    }
+
+    #[test]
+    fn tenant_shard_id_kv_key() -> Result<(), KvKeyError> {
+        let tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+        for id in [
+            TenantShardId::unsharded(tenant_id),
+            TenantShardId {
+                tenant_id,
+                shard_number: ShardNumber(7),
+                shard_count: ShardCount(10),
+            },
+        ] {
+            let key = id.kv_key();
+            assert_eq!(key.len(), 19);
+            assert_eq!(&key[1..], &id.to_bytes());
+            assert_eq!(TenantShardId::from_kv_key(&key)?, id);
+        }
+
+        let mut key = TenantShardId::unsharded(tenant_id).kv_key();
+        key[0] = 0x02;
+        assert_eq!(
+            TenantShardId::from_kv_key(&key),
+            Err(KvKeyError::WrongTag(0x02))
+        );
+        assert_eq!(
+            TenantShardId::from_kv_key(&[TENANT_SHARD_ID_KV_TAG, 0x00]),
+            Err(KvKeyError::BadLength(2))
+        );
+        assert_eq!(
+            TenantShardId::from_kv_key(&[]),
+            Err(KvKeyError::BadLength(0))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn shard_identity_resolve_index() -> Result<(), ShardConfigError> {
+        let identity = ShardIdentity::new(ShardNumber(0), ShardCount(4), ShardStripeSize(8))?;
+        for blkno in (0..1024).step_by(8) {
+            let key = rel_block_key(16384, blkno);
+            let index = identity.resolve_index(&key);
+            let number = identity.get_shard_number(&key);
+            assert_eq!(index.get_suffix(), format!("-{:02x}04", number.0));
+        }
+
+        let key = rel_block_key(16384, 0);
+        assert_eq!(
+            ShardIdentity::unsharded().resolve_index(&key),
+            ShardIndex::unsharded()
+        );
+        assert_eq!(
+            ShardIdentity::unsharded().resolve_index(&key).get_suffix(),
+            ""
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn all_tenant_shards_product() {
+        let tenants = [
+            TenantId::generate(),
+            TenantId::generate(),
+            TenantId::generate(),
+        ];
+        let ids = all_tenant_shards(&tenants, ShardCount(2));
+        assert_eq!(ids.len(), 6);
+        for tenant_id in tenants {
+            for number in 0..2 {
+                let id = TenantShardId {
+                    tenant_id,
+                    shard_number: ShardNumber(number),
+                    shard_count: ShardCount(2),
+                };
+                assert!(ids.contains(&id));
+                assert!(id.canonical_bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn tenant_shard_id_eq_ignoring_legacy() {
+        let tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+        let shard = |number, count| TenantShardId {
+            tenant_id,
+            shard_number: ShardNumber(number),
+            shard_count: ShardCount(count),
+        };
+        let legacy = TenantShardId::unsharded(tenant_id);
+
+        assert!(legacy.eq_ignoring_legacy(&shard(0, 1)));
+        assert!(shard(0, 1).eq_ignoring_legacy(&legacy));
+        assert!(!shard(0, 1).eq_ignoring_legacy(&shard(1, 4)));
+        assert!(!legacy.eq_ignoring_legacy(&shard(0, 4)));
+        assert!(shard(1, 4).eq_ignoring_legacy(&shard(1, 4)));
+        assert!(!legacy.eq_ignoring_legacy(&TenantShardId::unsharded(TenantId::generate())));
+    }
+
+    #[test]
+    fn tenant_shard_id_staggered_offset() {
+        let tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+        let window = Duration::from_secs(100);
+        let count = ShardCount(8);
+        let shard = |number| TenantShardId {
+            tenant_id,
+            shard_number: ShardNumber(number),
+            shard_count: count,
+        };
+
+        assert_eq!(shard(0).staggered_offset(count, window), Duration::ZERO);
+        assert_eq!(
+            shard(4).staggered_offset(count, window),
+            Duration::from_secs(50)
+        );
+        let last = shard(7).staggered_offset(count, window);
+        assert!(last < window && last >= window * 7 / 8);
+
+        // Windows too long to multiply by the shard number do not overflow
+        let last = shard(7).staggered_offset(count, Duration::MAX);
+        assert!(last < Duration::MAX && last >= Duration::MAX / 8 * 7);
+
+        assert_eq!(
+            TenantShardId::unsharded(tenant_id).staggered_offset(ShardCount(0), window),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn shard_identity_split_overflow() -> Result<(), ShardConfigError> {
+        let identity =
+            |count| ShardIdentity::new(ShardNumber(0), ShardCount(count), DEFAULT_STRIPE_SIZE);
+
+        assert!(identity(128)?.would_overflow_on_split(2));
+        assert!(!identity(4)?.would_overflow_on_split(2));
+        assert!(!identity(127)?.would_overflow_on_split(2));
+        assert!(!identity(85)?.would_overflow_on_split(3));
+        assert!(identity(86)?.would_overflow_on_split(3));
+        assert!(!ShardIdentity::unsharded().would_overflow_on_split(255));
+
+        Ok(())
+    }
+
+    #[test]
+    fn shard_index_color() {
+        let colors: Vec<_> = (0..4)
+            .map(|n| ShardIndex::new(ShardNumber(n), ShardCount(4)).color_rgb())
+            .collect();
+        assert_eq!(
+            colors,
+            (0..4)
+                .map(|n| ShardIndex::new(ShardNumber(n), ShardCount(4)).color_rgb())
+                .collect::<Vec<_>>()
+        );
+
+        let distinct: BTreeSet<_> = colors.iter().collect();
+        assert_eq!(distinct.len(), colors.len());
+    }
+
+    #[test]
+    fn packed_id_diff() {
+        let tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+        let a = TenantShardId {
+            tenant_id,
+            shard_number: ShardNumber(1),
+            shard_count: ShardCount(4),
+        };
+        let b = TenantShardId {
+            shard_count: ShardCount(8),
+            ..a
+        };
+
+        assert!(packed_diff(a.to_bytes(), a.to_bytes()).is_empty());
+        assert_eq!(
+            packed_diff(a.to_bytes(), b.to_bytes()),
+            vec![(17, 0x04, 0x08)]
+        );
+    }
+
+    #[test]
+    fn shard_index_validate_for_storage() -> Result<(), hex::FromHexError> {
+        assert_eq!(
+            ShardIndex::from_str("0000")?.validate_for_storage(),
+            Err(ShardConfigError::InvalidCount)
+        );
+        assert_eq!(ShardIndex::from_str("0001")?.validate_for_storage(), Ok(()));
+        assert_eq!(ShardIndex::from_str("0304")?.validate_for_storage(), Ok(()));
+        assert_eq!(
+            ShardIndex::from_str("0404")?.validate_for_storage(),
+            Err(ShardConfigError::InvalidNumber)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn shard_for_slot() -> Result<(), ShardConfigError> {
+        let identity = ShardIdentity::new(ShardNumber(0), ShardCount(4), ShardStripeSize(8))?;
+
+        for stripe in 0..16u64 {
+            let expect = ShardNumber((stripe % 4) as u8);
+            for slot in stripe * 8..(stripe + 1) * 8 {
+                assert_eq!(identity.shard_for_slot(slot), expect);
+            }
+        }
+        assert_eq!(
+            ShardIdentity::unsharded().shard_for_slot(1234),
+            ShardNumber(0)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn shard_routing_table() -> Result<(), ShardSetError> {
+        let mut identities: Vec<_> = shard_identities(ShardCount(4), ShardStripeSize(8))
+            .map_err(ShardSetError::InvalidIdentity)?
+            .collect();
+        identities.reverse();
+        let table = ShardRoutingTable::build(identities.clone())?;
+
+        for blkno in (0..1024).step_by(5) {
+            let key = rel_block_key(16384, blkno);
+            let identity = table.route(&key);
+            assert_eq!(identity.get_shard_number(&key), identity.number);
+            assert!(identities.contains(identity));
+        }
+
+        let table = ShardRoutingTable::build(vec![ShardIdentity::unsharded()])?;
+        assert_eq!(
+            table.route(&rel_block_key(16384, 0)),
+            &ShardIdentity::unsharded()
+        );
+
+        assert_eq!(
+            ShardRoutingTable::build(identities[0..2].to_vec()).err(),
+            Some(ShardSetError::WrongSize {
+                expected: 4,
+                found: 2
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn shard_index_from_suffix() -> Result<(), ShardIndexParseError> {
+        for index in [
+            ShardIndex::unsharded(),
+            ShardIndex::new(ShardNumber(1), ShardCount(4)),
+        ] {
+            assert_eq!(ShardIndex::from_suffix(&index.get_suffix())?, index);
+        }
+        assert_eq!(ShardIndex::from_suffix("")?, ShardIndex::unsharded());
+        assert_eq!(
+            ShardIndex::from_suffix("-0104")?,
+            ShardIndex::new(ShardNumber(1), ShardCount(4))
+        );
+
+        assert_eq!(
+            ShardIndex::from_suffix("0104"),
+            Err(ShardIndexParseError::MissingSeparator)
+        );
+        assert_eq!(
+            ShardIndex::from_suffix("-010"),
+            Err(ShardIndexParseError::BadIndex(
+                hex::FromHexError::InvalidStringLength
+            ))
+        );
+        assert_eq!(
+            ShardIndex::from_suffix("-0000"),
+            Err(ShardIndexParseError::ExplicitUnsharded)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_ownership_during_split() {
+        let keys: Vec<Key> = (0..1024).map(|blkno| rel_block_key(16384, blkno)).collect();
+        let stripe_size = ShardStripeSize(8);
+        let tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+        let parent = TenantShardId {
+            tenant_id,
+            shard_number: ShardNumber(0),
+            shard_count: ShardCount(2),
+        };
+        let child = TenantShardId {
+            tenant_id,
+            shard_number: ShardNumber(2),
+            shard_count: ShardCount(4),
+        };
+        assert!(concurrent_ownership(
+            &parent,
+            &child,
+            &keys,
+            stripe_size,
+            stripe_size
+        ));
+
+        let unrelated = TenantShardId {
+            tenant_id: TenantId::generate(),
+            ..child
+        };
+        assert!(!concurrent_ownership(
+            &parent,
+            &unrelated,
+            &keys,
+            stripe_size,
+            stripe_size
+        ));
+    }
+
+    #[test]
+    fn shard_count_drain_order() {
+        let count = ShardCount(4);
+        assert_eq!(count.drain_order(), [3, 2, 1, 0].map(ShardNumber).to_vec());
+        assert_eq!(
+            count.drain_order_seeded(1),
+            [2, 1, 0, 3].map(ShardNumber).to_vec()
+        );
+        assert_eq!(count.drain_order_seeded(4), count.drain_order());
+
+        for seed in 0..8 {
+            let mut order = count.drain_order_seeded(seed);
+            order.sort();
+            assert_eq!(order, (0..4).map(ShardNumber).collect::<Vec<_>>());
+        }
+
+        assert_eq!(ShardCount(0).drain_order(), vec![ShardNumber(0)]);
+    }
+
+    #[test]
+    fn shard_count_expected_index_parts() {
+        assert_eq!(ShardCount(0).expected_index_parts(3), 3);
+        assert_eq!(ShardCount(1).expected_index_parts(3), 3);
+        assert_eq!(ShardCount(4).expected_index_parts(2), 8);
+        assert_eq!(ShardCount(4).expected_index_parts(0), 0);
+    }
+
+    #[test]
+    fn tenant_shard_id_matches_bytes() -> Result<(), hex::FromHexError> {
+        let parsed = TenantShardId::from_str(&format!("{EXAMPLE_TENANT_ID}-070a"))?;
+        let mut expected: [u8; 18] = [
+            0x1f, 0x35, 0x9d, 0xd6, 0x25, 0xe5, 0x19, 0xa1, 0xa4, 0xe8, 0xd7, 0x50, 0x96, 0x90,
+            0xf6, 0xfc, 0x07, 0x0a,
+        ];
+        assert!(parsed.matches_bytes(&expected));
+
+        expected[17] = 0x0b;
+        assert!(!parsed.matches_bytes(&expected));
+
+        Ok(())
+    }
+
+    #[test]
+    fn route_key_to_tenant_shard() -> Result<(), ShardConfigError> {
+        let tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+        let identity = ShardIdentity::new(ShardNumber(1), ShardCount(4), ShardStripeSize(8))?;
+
+        for blkno in (0..1024).step_by(8) {
+            let key = rel_block_key(16384, blkno);
+            let routed = route_key(tenant_id, &key, &identity);
+            assert_eq!(routed, identity.resolve(tenant_id, &key));
+            assert_eq!(
+                routed,
+                TenantShardId {
+                    tenant_id,
+                    shard_number: identity.get_shard_number(&key),
+                    shard_count: ShardCount(4),
+                }
+            );
+        }
+
+        let key = rel_block_key(16384, 0);
+        assert_eq!(
+            route_key(tenant_id, &key, &ShardIdentity::unsharded()),
+            TenantShardId::unsharded(tenant_id)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn shard_layout_upgrade_needed() {
+        assert_eq!(ShardLayout::upgrade_needed_for(200), None);
+        assert_eq!(ShardLayout::upgrade_needed_for(255), None);
+        assert_eq!(ShardLayout::upgrade_needed_for(256), Some(LAYOUT_V2));
+        assert_eq!(ShardLayout::upgrade_needed_for(300), Some(LAYOUT_V2));
+    }
+
+    #[test]
+    fn locality_from_histogram() -> Result<(), ShardConfigError> {
+        let rel = RelTag {
+            forknum: 0,
+            spcnode: 1663,
+            dbnode: 5,
+            relnode: 16384,
+        };
+        let histogram: Vec<u64> = (1..=32).collect();
+        let shard0 = ShardIdentity::new(ShardNumber(0), ShardCount(2), ShardStripeSize(8))?;
+        let shard1 = ShardIdentity::new(ShardNumber(1), ShardCount(2), ShardStripeSize(8))?;
+
+        let local0 = shard0.locality_from_histogram(rel, &histogram);
+        let local1 = shard1.locality_from_histogram(rel, &histogram);
+        assert!(local0 > 0.0 && local1 > 0.0);
+        assert!((local0 + local1 - 1.0).abs() < 1e-9);
+
+        // Agrees with routing the keys themselves
+        let total: u64 = histogram.iter().sum();
+        let routed: u64 = histogram
+            .iter()
+            .enumerate()
+            .filter(|(stripe, _)| {
+                shard0.get_shard_number(&rel_block_to_key(rel, *stripe as u32 * 8))
+                    == ShardNumber(0)
+            })
+            .map(|(_, keys)| keys)
+            .sum();
+        assert_eq!(local0, routed as f64 / total as f64);
+
+        assert_eq!(
+            ShardIdentity::unsharded().locality_from_histogram(rel, &histogram),
+            1.0
+        );
+        assert_eq!(shard0.locality_from_histogram(rel, &[]), 0.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn split_plan_validation() {
+        let plan = |counts: &[u8]| counts.iter().map(|c| ShardCount(*c)).collect::<Vec<_>>();
+
+        assert_eq!(validate_split_plan(ShardCount(1), &plan(&[4, 16])), Ok(()));
+        assert_eq!(
+            validate_split_plan(ShardCount(0), &plan(&[2, 8, 128])),
+            Ok(())
+        );
+        assert_eq!(validate_split_plan(ShardCount(4), &[]), Ok(()));
+
+        assert_eq!(
+            validate_split_plan(ShardCount(1), &plan(&[4, 6])),
+            Err(ShardConfigError::InvalidCount)
+        );
+        assert_eq!(
+            validate_split_plan(ShardCount(4), &plan(&[4])),
+            Err(ShardConfigError::InvalidCount)
+        );
+        assert_eq!(
+            validate_split_plan(ShardCount(8), &plan(&[4])),
+            Err(ShardConfigError::InvalidCount)
+        );
+        // The largest count reachable by splitting from 128 would be 256, beyond ShardCount::MAX
+        assert_eq!(
+            validate_split_plan(ShardCount(128), &plan(&[255])),
+            Err(ShardConfigError::InvalidCount)
+        );
+    }
+
+    #[test]
+    fn tenant_shard_id_cache_key() {
+        let tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+        let shard = |number| TenantShardId {
+            tenant_id,
+            shard_number: ShardNumber(number),
+            shard_count: ShardCount(4),
+        };
+
+        for id in [TenantShardId::unsharded(tenant_id), shard(3)] {
+            for sub in [0, 1, u64::MAX] {
+                assert_eq!(
+                    TenantShardId::parse_cache_key(&id.cache_key(sub)),
+                    (id, sub)
+                );
+            }
+        }
+
+        let mut keys = [
+            shard(1).cache_key(7),
+            shard(0).cache_key(0x100),
+            shard(1).cache_key(2),
+            shard(0).cache_key(3),
+        ];
+        keys.sort();
+        let decoded: Vec<_> = keys.iter().map(TenantShardId::parse_cache_key).collect();
+        assert_eq!(
+            decoded,
+            vec![
+                (shard(0), 3),
+                (shard(0), 0x100),
+                (shard(1), 2),
+                (shard(1), 7)
+            ]
+        );
+    }
+
+    #[test]
+    fn key_distribution() -> Result<(), ShardConfigError> {
+        let identities: Vec<_> = shard_identities(ShardCount(4), ShardStripeSize(8))?.collect();
+        let mut local = [0usize; 4];
+        let mut total = 0;
+        for relnode in 16384..16394 {
+            for blkno in 0..8192 {
+                let key = rel_block_key(relnode, blkno);
+                let owners: Vec<_> = identities.iter().filter(|i| i.is_key_local(&key)).collect();
+                assert_eq!(owners.len(), 1);
+                local[owners[0].number.0 as usize] += 1;
+                total += 1;
+            }
+        }
+
+        // Each shard gets roughly a quarter of the keys
+        for n in local {
+            let share = n as f64 / total as f64;
+            assert!((0.2..0.3).contains(&share), "{local:?}");
+        }
+
+        // The unsharded identity claims everything
+        let unsharded = ShardIdentity::unsharded();
+        for blkno in (0..8192).step_by(13) {
+            assert!(unsharded.is_key_local(&rel_block_key(16384, blkno)));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn owns_majority_of_range() -> Result<(), ShardConfigError> {
+        let stripe_size = ShardStripeSize(8);
+        let shard0 = ShardIdentity::new(ShardNumber(0), ShardCount(2), stripe_size)?;
+        let shard1 = ShardIdentity::new(ShardNumber(1), ShardCount(2), stripe_size)?;
+
+        // Find a run of three stripes of which shard 0 owns at least two
+        let stripe_start = |stripe: u32| rel_block_key(16384, stripe * stripe_size.0);
+        let first = (0..)
+            .find(|s| {
+                (*s..s + 3)
+                    .filter(|s| shard0.is_key_local(&stripe_start(*s)))
+                    .count()
+                    >= 2
+            })
+            .unwrap();
+        let range = stripe_start(first)..stripe_start(first + 3);
+
+        assert!(shard0.owns_majority_of_range(range.clone()));
+        assert!(!shard1.owns_majority_of_range(range.clone()));
+        assert!(ShardIdentity::unsharded().owns_majority_of_range(range));
+
+        Ok(())
+    }
+
+    #[test]
+    fn shard_map_strings() {
+        let tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+        assert_eq!(
+            shard_map_string(tenant_id, ShardCount(4)),
+            "1f35...f6fc[0-3/4]"
+        );
+        assert_eq!(shard_map_string(tenant_id, ShardCount(1)), "1f35...f6fc");
+        assert_eq!(shard_map_string(tenant_id, ShardCount(0)), "1f35...f6fc");
+    }
+
+    #[test]
+    fn shard_count_shard_numbers() {
+        assert_eq!(ShardCount(8).count(), 8);
+        assert_eq!(
+            ShardCount(0).shard_numbers().collect::<Vec<_>>(),
+            vec![ShardNumber(0)]
+        );
+        assert_eq!(
+            ShardCount(1).shard_numbers().collect::<Vec<_>>(),
+            vec![ShardNumber(0)]
+        );
+        assert_eq!(
+            ShardCount(8).shard_numbers().collect::<Vec<_>>(),
+            (0..8).map(ShardNumber).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn shard_identity_layout_constraints() -> Result<(), ShardConfigError> {
+        let v1 = ShardIdentity::new(ShardNumber(0), ShardCount(4), ShardStripeSize(1000))?;
+        assert_eq!(v1.validate_layout_constraints(), Ok(()));
+
+        let v2 = ShardIdentity {
+            layout: LAYOUT_V2,
+            ..v1
+        };
+        assert_eq!(
+            v2.validate_layout_constraints(),
+            Err(ShardConfigError::InvalidStripeSize)
+        );
+        let v2 = ShardIdentity {
+            stripe_size: ShardStripeSize(1024),
+            ..v2
+        };
+        assert_eq!(
+            v2.validate_layout_constraints(),
+            Err(ShardConfigError::InvalidLayout)
+        );
+
+        let unknown = ShardIdentity {
+            layout: ShardLayout(99),
+            ..v1
+        };
+        assert_eq!(
+            unknown.validate_layout_constraints(),
+            Err(ShardConfigError::InvalidLayout)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn shard_identity_deserialize_validates_layout() -> Result<(), ShardConfigError> {
+        let v1 = ShardIdentity::new(ShardNumber(1), ShardCount(4), ShardStripeSize(1000))?;
+        let encoded = serde_json::to_string(&v1).unwrap();
+        assert_eq!(serde_json::from_str::<ShardIdentity>(&encoded).unwrap(), v1);
+
+        let v2 = ShardIdentity {
+            layout: LAYOUT_V2,
+            ..v1
+        };
+        let encoded = serde_json::to_string(&v2).unwrap();
+        assert!(serde_json::from_str::<ShardIdentity>(&encoded).is_err());
+
+        // The same checks as ShardIdentity::new, except that the legacy form is allowed
+        let legacy = r#"{"layout":1,"number":0,"count":0,"stripe_size":32768}"#;
+        assert_eq!(
+            serde_json::from_str::<ShardIdentity>(legacy).unwrap(),
+            ShardIdentity::unsharded()
+        );
+        for invalid in [
+            r#"{"layout":1,"number":1,"count":4,"stripe_size":0}"#,
+            r#"{"layout":1,"number":0,"count":0,"stripe_size":0}"#,
+            r#"{"layout":1,"number":4,"count":4,"stripe_size":1000}"#,
+            r#"{"layout":1,"number":1,"count":0,"stripe_size":1000}"#,
+        ] {
+            assert!(serde_json::from_str::<ShardIdentity>(invalid).is_err());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn tenant_shard_id_parse_errors() {
+        assert_eq!(
+            TenantShardId::from_str(&format!("{EXAMPLE_TENANT_ID}0102")),
+            Err(TenantShardIdParseError::BadLength { got: 36 })
+        );
+        assert_eq!(
+            TenantShardId::from_str(""),
+            Err(TenantShardIdParseError::BadLength { got: 0 })
+        );
+        assert_eq!(
+            TenantShardId::from_str(&format!("{EXAMPLE_TENANT_ID}_0102")),
+            Err(TenantShardIdParseError::MissingSeparator)
+        );
+        assert!(matches!(
+            TenantShardId::from_str(&format!("{}x-0102", &EXAMPLE_TENANT_ID[..31])),
+            Err(TenantShardIdParseError::BadTenantId(_))
+        ));
+        assert!(matches!(
+            TenantShardId::from_str(&format!("{}x", &EXAMPLE_TENANT_ID[..31])),
+            Err(TenantShardIdParseError::BadTenantId(_))
+        ));
+        assert!(matches!(
+            TenantShardId::from_str(&format!("{EXAMPLE_TENANT_ID}-01zz")),
+            Err(TenantShardIdParseError::BadShardSuffix(_))
+        ));
+
+        // Callers that still want a hex error get the closest equivalent
+        assert_eq!(
+            hex::FromHexError::from(TenantShardIdParseError::MissingSeparator),
+            hex::FromHexError::InvalidStringLength
+        );
+    }
+
+    #[test]
+    fn split_new_shard_numbers() {
+        assert_eq!(
+            new_shard_numbers(ShardCount(2), ShardCount(4)),
+            Ok(vec![ShardNumber(2), ShardNumber(3)])
+        );
+        // A legacy unsharded tenant already has shard zero
+        assert_eq!(
+            new_shard_numbers(ShardCount(0), ShardCount(2)),
+            Ok(vec![ShardNumber(1)])
+        );
+        assert_eq!(
+            new_shard_numbers(ShardCount(4), ShardCount(4)),
+            Err(ShardConfigError::InvalidCount)
+        );
+        assert_eq!(
+            new_shard_numbers(ShardCount(4), ShardCount(6)),
+            Err(ShardConfigError::InvalidCount)
+        );
+    }
+
+    #[test]
+    fn tenant_shard_id_split() -> Result<(), ShardSplitError> {
+        let tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+        let shard = |number, count| TenantShardId {
+            tenant_id,
+            shard_number: ShardNumber(number),
+            shard_count: ShardCount(count),
+        };
+
+        assert_eq!(
+            shard(0, 1).split(ShardCount(4))?,
+            vec![shard(0, 4), shard(1, 4), shard(2, 4), shard(3, 4)]
+        );
+        assert_eq!(
+            shard(1, 2).split(ShardCount(8))?,
+            vec![shard(1, 8), shard(3, 8), shard(5, 8), shard(7, 8)]
+        );
+        assert_eq!(
+            shard(0, 4).split(ShardCount(8))?,
+            vec![shard(0, 8), shard(4, 8)]
+        );
+        assert_eq!(
+            TenantShardId::unsharded(tenant_id).split(ShardCount(2))?,
+            vec![shard(0, 2), shard(1, 2)]
+        );
+
+        assert_eq!(
+            shard(0, 3).split(ShardCount(8)),
+            Err(ShardSplitError::NotMultiple {
+                old: ShardCount(3),
+                new: ShardCount(8)
+            })
+        );
+        assert_eq!(
+            shard(0, 4).split(ShardCount(4)),
+            Err(ShardSplitError::NotLarger {
+                old: ShardCount(4),
+                new: ShardCount(4)
+            })
+        );
+
+        // Children own exactly the keys their parent owned
+        let parent =
+            ShardIdentity::new(ShardNumber(1), ShardCount(2), DEFAULT_STRIPE_SIZE).unwrap();
+        let children: Vec<_> = shard(1, 2)
+            .split(ShardCount(8))?
+            .iter()
+            .map(|c| {
+                ShardIdentity::new(c.shard_number, c.shard_count, DEFAULT_STRIPE_SIZE).unwrap()
+            })
+            .collect();
+        for blkno in (0..64).map(|i| i * DEFAULT_STRIPE_SIZE.0) {
+            let key = rel_block_key(1000, blkno);
+            assert_eq!(
+                parent.is_key_local(&key),
+                children.iter().any(|c| c.is_key_local(&key))
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn tenant_shard_id_parse_diagnostic() {
+        let good = format!("{EXAMPLE_TENANT_ID}-0104");
+        assert_eq!(
+            TenantShardId::parse_diagnostic(&good),
+            Ok(TenantShardId::from_str(&good).unwrap())
+        );
+
+        let bad = format!("{}x_0504", &EXAMPLE_TENANT_ID[..31]);
+        assert_eq!(
+            TenantShardId::parse_diagnostic(&bad),
+            Err(vec![
+                "invalid hex character 'x' at position 31".to_string(),
+                "expected '-' at position 32, found '_'".to_string(),
+                "shard number 5 is not less than shard count 4".to_string(),
+            ])
+        );
+
+        let short = format!("{}g", &EXAMPLE_TENANT_ID[..30]);
+        assert_eq!(
+            TenantShardId::parse_diagnostic(&short),
+            Err(vec![
+                "expected 32, 37 or 41 characters, got 31".to_string(),
+                "invalid hex character 'g' at position 30".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn tenant_shard_id_ordering() {
+        let tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+        let shard = |tenant_id, number, count| TenantShardId {
+            tenant_id,
+            shard_number: ShardNumber(number),
+            shard_count: ShardCount(count),
+        };
+
+        // Coarser splits sort first, regardless of shard number
+        assert!(shard(tenant_id, 1, 2) < shard(tenant_id, 0, 4));
+
+        let mut below = tenant_id.as_arr();
+        below[15] -= 1;
+        let below = TenantId::from(below);
+        let mut above = tenant_id.as_arr();
+        above[15] += 1;
+        let above = TenantId::from(above);
+
+        let ours = [
+            shard(tenant_id, 0, 0),
+            shard(tenant_id, 1, 2),
+            shard(tenant_id, 0, 4),
+            shard(tenant_id, 3, 4),
+            shard(tenant_id, 7, 8),
+        ];
+        let mut map = BTreeMap::new();
+        for id in ours.iter().chain(&[
+            shard(below, 255, 255),
+            shard(below, 3, 4),
+            shard(above, 0, 0),
+            shard(above, 1, 2),
+        ]) {
+            map.insert(*id, ());
+        }
+
+        let found: Vec<_> = map
+            .range(TenantShardId::tenant_range(tenant_id))
+            .map(|(id, _)| *id)
+            .collect();
+        assert_eq!(found, ours);
+    }
+
+    #[test]
+    fn tenant_shard_id_try_from_slice() {
+        let id = TenantShardId {
+            tenant_id: TenantId::from_str(EXAMPLE_TENANT_ID).unwrap(),
+            shard_number: ShardNumber(3),
+            shard_count: ShardCount(8),
+        };
+        let mut bytes = id.to_bytes().to_vec();
+
+        assert_eq!(TenantShardId::try_from(bytes.as_slice()), Ok(id));
+        assert_eq!(
+            TenantShardId::try_from(&bytes[..17]),
+            Err(TenantShardIdBytesError::BadLength(17))
+        );
+        bytes.push(0);
+        assert_eq!(
+            TenantShardId::try_from(bytes.as_slice()),
+            Err(TenantShardIdBytesError::BadLength(19))
+        );
+    }
+
+    #[test]
+    fn global_keys_on_shard_zero() -> Result<(), ShardConfigError> {
+        let identity = ShardIdentity::new(ShardNumber(0), ShardCount(4), DEFAULT_STRIPE_SIZE)?;
+        let router = ShardRouter::new(&identity)?;
+        let misc_key = |field6| Key {
+            field1: 0x03,
+            field2: 0,
+            field3: 0,
+            field4: 0,
+            field5: 0,
+            field6,
+        };
+        let controlfile = misc_key(0);
+        let checkpoint = misc_key(1);
+        let dbdir = Key {
+            field1: 0x00,
+            ..controlfile
+        };
+        let reldir = Key {
+            field3: 1,
+            field6: 1,
+            ..dbdir
+        };
+
+        for key in [controlfile, checkpoint, dbdir, reldir] {
+            assert!(ShardIdentity::is_global_key(&key));
+            assert_eq!(identity.get_shard_number(&key), ShardNumber(0));
+            assert_eq!(router.route(&key), ShardNumber(0));
+        }
+
+        // Other global keys too, wherever their hash would put them
+        for field4 in 0..64 {
+            let key = Key {
+                field1: 0x02,
+                field4,
+                ..controlfile
+            };
+            assert_eq!(identity.get_shard_number(&key), ShardNumber(0));
+            assert_eq!(router.route(&key), ShardNumber(0));
+        }
+
+        // Relation blocks still spread across all shards
+        let shards: BTreeSet<_> = (0..64)
+            .map(|i| identity.get_shard_number(&rel_block_key(1000, i * DEFAULT_STRIPE_SIZE.0)))
+            .collect();
+        assert_eq!(shards.len(), 4);
+        assert!(!ShardIdentity::is_global_key(&rel_block_key(1000, 0)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn shards_present_bitmap_roundtrip() {
+        let present = [ShardNumber(0), ShardNumber(2), ShardNumber(5)];
+        let bitmap = shards_present_bitmap(&present, ShardCount(8));
+        assert_eq!(bitmap, vec![0b0010_0101]);
+        assert_eq!(shards_from_bitmap(&bitmap, ShardCount(8)), present);
+
+        let present = [ShardNumber(1), ShardNumber(9)];
+        let bitmap = shards_present_bitmap(&present, ShardCount(10));
+        assert_eq!(bitmap, vec![0b0000_0010, 0b0000_0010]);
+        assert_eq!(shards_from_bitmap(&bitmap, ShardCount(10)), present);
+
+        // A legacy unsharded tenant's only shard still gets a bit
+        let bitmap = shards_present_bitmap(&[ShardNumber(0)], ShardCount(0));
+        assert_eq!(
+            shards_from_bitmap(&bitmap, ShardCount(0)),
+            vec![ShardNumber(0)]
+        );
+    }
+
+    #[test]
+    fn metadata_keys_on_shard_zero() -> Result<(), ShardConfigError> {
+        let controlfile = Key {
+            field1: 0x03,
+            field2: 0,
+            field3: 0,
+            field4: 0,
+            field5: 0,
+            field6: 0,
+        };
+        let rel_size = Key {
+            field6: 0xffffffff,
+            ..rel_block_key(1000, 0)
+        };
+        let slru_block = Key {
+            field1: 0x01,
+            ..rel_block_key(1000, 0)
+        };
+        for key in [controlfile, rel_size, slru_block] {
+            assert!(ShardIdentity::is_metadata_key(&key));
+        }
+        assert!(!ShardIdentity::is_metadata_key(&rel_block_key(1000, 0)));
+
+        for count in [1, 2, 4] {
+            for number in 0..count {
+                let identity = ShardIdentity::new(
+                    ShardNumber(number),
+                    ShardCount(count),
+                    DEFAULT_STRIPE_SIZE,
+                )?;
+                assert_eq!(identity.get_shard_number(&controlfile), ShardNumber(0));
+                assert_eq!(identity.is_key_local(&controlfile), number == 0);
+            }
+        }
+
+        // Relation sizes stay on shard zero wherever their relation's blocks are striped
+        let identity = ShardIdentity::new(ShardNumber(0), ShardCount(4), DEFAULT_STRIPE_SIZE)?;
+        let router = ShardRouter::new(&identity)?;
+        for relnode in 1000..1064 {
+            let key = Key {
+                field6: 0xffffffff,
+                ..rel_block_key(relnode, 0)
+            };
+            assert_eq!(identity.get_shard_number(&key), ShardNumber(0));
+            assert_eq!(router.route(&key), ShardNumber(0));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn tenant_shard_id_string_with_suffix() -> Result<(), hex::FromHexError> {
+        let tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+        let legacy = TenantShardId::unsharded(tenant_id);
+        let single = TenantShardId {
+            tenant_id,
+            shard_number: ShardNumber(0),
+            shard_count: ShardCount(1),
+        };
+
+        assert_eq!(legacy.to_string(), EXAMPLE_TENANT_ID);
+        assert_eq!(
+            legacy.to_string_with_suffix(),
+            format!("{EXAMPLE_TENANT_ID}-0000")
+        );
+        assert_eq!(
+            single.to_string_with_suffix(),
+            format!("{EXAMPLE_TENANT_ID}-0001")
+        );
+        assert_eq!(single.to_string_with_suffix(), single.to_string());
+
+        // The explicit form still parses back to the same id
+        assert_eq!(
+            TenantShardId::from_str(&legacy.to_string_with_suffix())?,
+            legacy
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn tenant_shard_id_sort_distance() {
+        let tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+        let shard = |tenant_id, number, count| TenantShardId {
+            tenant_id,
+            shard_number: ShardNumber(number),
+            shard_count: ShardCount(count),
+        };
+
+        assert_eq!(
+            shard(tenant_id, 3, 8).sort_distance(&shard(tenant_id, 2, 8)),
+            1
+        );
+        assert_eq!(
+            shard(tenant_id, 2, 8).sort_distance(&shard(tenant_id, 3, 8)),
+            -1
+        );
+        // The shard count is the more significant byte of the two
+        assert_eq!(
+            shard(tenant_id, 0, 8).sort_distance(&shard(tenant_id, 0, 4)),
+            4 << 8
+        );
+
+        // The sign agrees with Ord
+        let pairs = [((1, 2), (0, 4)), ((0, 4), (1, 2)), ((3, 4), (0, 8))];
+        for ((a_number, a_count), (b_number, b_count)) in pairs {
+            let a = shard(tenant_id, a_number, a_count);
+            let b = shard(tenant_id, b_number, b_count);
+            assert_eq!(a.sort_distance(&b).cmp(&0), a.cmp(&b));
+        }
+
+        // The tenant id is the most significant
+        let mut next = tenant_id.as_arr();
+        next[15] += 1;
+        let next = TenantId::from(next);
+        let a = shard(tenant_id, 255, 255);
+        let b = shard(next, 0, 0);
+        assert!(a < b);
+        assert_eq!(b.sort_distance(&a), 1);
+
+        // Far apart tenants saturate rather than overflowing
+        let min = shard(TenantId::from([0; 16]), 0, 0);
+        let max = shard(TenantId::from([0xff; 16]), 0xff, 0xff);
+        assert_eq!(max.sort_distance(&min), i128::MAX);
+        assert_eq!(min.sort_distance(&max), i128::MIN);
+    }
+
+    #[test]
+    fn shard_identity_u64_roundtrip() -> Result<(), ShardConfigError> {
+        let identity = ShardIdentity::new(ShardNumber(3), ShardCount(8), DEFAULT_STRIPE_SIZE)?;
+        assert_eq!(ShardIdentity::from_u64_strict(identity.to_u64())?, identity);
+        let legacy = ShardIdentity::unsharded();
+        assert_eq!(ShardIdentity::from_u64_strict(legacy.to_u64())?, legacy);
+
+        let unknown_layout = (identity.to_u64() & !(0xff << 56)) | (99 << 56);
+        assert_eq!(
+            ShardIdentity::from_u64_strict(unknown_layout),
+            Err(ShardConfigError::InvalidLayout)
+        );
+        let bad_number = (identity.to_u64() & !(0xff << 48)) | (8 << 48);
+        assert_eq!(
+            ShardIdentity::from_u64_strict(bad_number),
+            Err(ShardConfigError::InvalidNumber)
+        );
+        assert_eq!(
+            ShardIdentity::from_u64_strict(identity.to_u64() | (1 << 32)),
+            Err(ShardConfigError::InvalidStripeSize)
+        );
+        // Zero stripes are refused, even for the legacy unsharded identity
+        for zero_stripe in [identity, legacy].map(|i| i.to_u64() & !0xffff_ffff) {
+            assert_eq!(
+                ShardIdentity::from_u64_strict(zero_stripe),
+                Err(ShardConfigError::InvalidStripeSize)
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn tenant_shard_id_parent() -> Result<(), ShardMergeError> {
+        let tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+        let shard = |number, count| TenantShardId {
+            tenant_id,
+            shard_number: ShardNumber(number),
+            shard_count: ShardCount(count),
+        };
+
+        assert_eq!(shard(4, 8).parent(ShardCount(4))?, shard(0, 4));
+        assert_eq!(shard(7, 8).parent(ShardCount(2))?, shard(1, 2));
+        assert_eq!(shard(3, 4).parent(ShardCount(0))?, shard(0, 0));
+
+        // parent() undoes split()
+        for child in shard(1, 2).split(ShardCount(8)).unwrap() {
+            assert_eq!(child.parent(ShardCount(2))?, shard(1, 2));
+        }
+
+        assert_eq!(
+            shard(5, 8).parent(ShardCount(3)),
+            Err(ShardMergeError::NotDivisor {
+                count: ShardCount(8),
+                old: ShardCount(3)
+            })
+        );
+        assert_eq!(
+            shard(1, 4).parent(ShardCount(8)),
+            Err(ShardMergeError::NotSmaller {
+                count: ShardCount(4),
+                old: ShardCount(8)
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn stripe_size_new() {
+        assert_eq!(
+            ShardStripeSize::new(0),
+            Err(ShardConfigError::InvalidStripeSize)
+        );
+        assert_eq!(ShardStripeSize::new(1), Ok(ShardStripeSize(1)));
+        assert_eq!(ShardStripeSize::new(1000), Ok(ShardStripeSize(1000)));
+
+        assert_eq!(DEFAULT_STRIPE_SIZE.in_bytes(8192), 256 * 1024 * 1024);
+        assert_eq!(
+            ShardStripeSize(u32::MAX).in_bytes(8192),
+            u32::MAX as u64 * 8192
+        );
+    }
+
+    #[test]
+    fn shards_for_relation_scan() -> Result<(), ShardConfigError> {
+        let rel = RelTag {
+            forknum: 0,
+            spcnode: 1663,
+            dbnode: 5,
+            relnode: 1000,
+        };
+        let count = ShardCount(4);
+
+        let big = ShardIdentity::shards_for_relation(
+            count,
+            DEFAULT_STRIPE_SIZE,
+            rel,
+            64 * DEFAULT_STRIPE_SIZE.0,
+        )?;
+        assert_eq!(big, count.shard_numbers().collect());
+
+        let tiny = ShardIdentity::shards_for_relation(count, DEFAULT_STRIPE_SIZE, rel, 10)?;
+        assert_eq!(tiny.len(), 1);
+        let identity = ShardIdentity::new(ShardNumber(0), count, DEFAULT_STRIPE_SIZE)?;
+        assert!(tiny.contains(&identity.shard_for_relation_block(rel, 0)));
+
+        assert!(ShardIdentity::shards_for_relation(count, DEFAULT_STRIPE_SIZE, rel, 0)?.is_empty());
+        assert_eq!(
+            ShardIdentity::shards_for_relation(count, ShardStripeSize(0), rel, 10),
+            Err(ShardConfigError::InvalidStripeSize)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn tenant_shard_id_canonical_str() {
+        assert!(TenantShardId::is_canonical_str(EXAMPLE_TENANT_ID));
+        assert!(TenantShardId::is_canonical_str(&format!(
+            "{EXAMPLE_TENANT_ID}-0104"
+        )));
+
+        assert!(!TenantShardId::is_canonical_str(
+            &EXAMPLE_TENANT_ID.to_uppercase()
+        ));
+        assert!(!TenantShardId::is_canonical_str(&format!(
+            "{EXAMPLE_TENANT_ID}-0A10"
+        )));
+        // Legacy tenants are written without a suffix
+        assert!(!TenantShardId::is_canonical_str(&format!(
+            "{EXAMPLE_TENANT_ID}-0000"
+        )));
+        assert!(!TenantShardId::is_canonical_str(
+            "1f359dd6-25e5-19a1-a4e8-d7509690f6fc"
+        ));
+    }
+
+    #[test]
+    fn shard_identity_defaults() -> Result<(), ShardConfigError> {
+        assert_eq!(
+            ShardIdentity::unsharded().stripe_size,
+            ShardStripeSize::default_value()
+        );
+        assert_eq!(ShardIdentity::unsharded().layout, ShardLayout::current());
+
+        let identity = ShardIdentity::new(ShardNumber(0), ShardCount(4), DEFAULT_STRIPE_SIZE)?;
+        assert_eq!(identity.layout, LAYOUT_V1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn unsharded_defaults() {
+        assert_eq!(ShardIndex::default(), ShardIndex::unsharded());
+        assert_eq!(ShardIdentity::default(), ShardIdentity::unsharded());
+
+        #[derive(Default)]
+        struct Config {
+            index: ShardIndex,
+            identity: ShardIdentity,
+        }
+        let config = Config::default();
+        assert!(config.index.is_unsharded());
+        assert!(config.identity.is_unsharded());
+    }
+
+    #[test]
+    fn stripe_boundaries_in_range() -> Result<(), ShardConfigError> {
+        let stripe_size = ShardStripeSize(1000);
+        let identity = ShardIdentity::new(ShardNumber(0), ShardCount(4), stripe_size)?;
+        let range = rel_block_key(1000, 500)..rel_block_key(1000, 64_250);
+
+        let boundaries = identity.stripe_boundaries_in(range.clone());
+        assert_eq!(boundaries.len(), 64);
+        for boundary in &boundaries {
+            assert!(range.contains(boundary));
+            assert_eq!(boundary.field6 % stripe_size.0, 0);
+        }
+        assert_eq!(boundaries[0].field6, 1000);
+        for pair in boundaries.windows(2) {
+            assert_eq!(pair[1].field6 - pair[0].field6, stripe_size.0);
+        }
+
+        // The owner can only change at a boundary
+        let mut edges = vec![range.start];
+        edges.extend(&boundaries);
+        edges.push(range.end);
+        let mut owners = BTreeSet::new();
+        for pair in edges.windows(2) {
+            let owner = identity.get_shard_number(&pair[0]);
+            owners.insert(owner);
+            for blkno in (pair[0].field6..pair[1].field6).step_by(stripe_size.0 as usize / 2) {
+                assert_eq!(
+                    identity.get_shard_number(&rel_block_key(1000, blkno)),
+                    owner
+                );
+            }
+        }
+        assert_eq!(owners.len(), 4);
+
+        // An unsharded tenant never needs to split reads
+        assert!(ShardIdentity::unsharded()
+            .stripe_boundaries_in(range)
+            .is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn tenant_shard_identity_pairs() -> Result<(), ShardConfigError> {
+        let tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+        let pairs = tenant_shard_identities(tenant_id, ShardCount(4), DEFAULT_STRIPE_SIZE)?;
+        assert_eq!(pairs.len(), 4);
+        for (id, identity) in &pairs {
+            assert_eq!(id.tenant_id, tenant_id);
+            assert_eq!(
+                ShardIndex::try_from_tenant_shard_id(id)?,
+                ShardIndex::new(identity.number, identity.count)
+            );
+            assert_eq!(identity.stripe_size, DEFAULT_STRIPE_SIZE);
+        }
+
+        assert_eq!(
+            tenant_shard_identities(tenant_id, ShardCount(0), DEFAULT_STRIPE_SIZE),
+            Err(ShardConfigError::InvalidCount)
+        );
+        assert_eq!(
+            tenant_shard_identities(tenant_id, ShardCount(4), ShardStripeSize(0)),
+            Err(ShardConfigError::InvalidStripeSize)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn tenant_shard_id_to_index() {
+        let tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+        let sharded = TenantShardId {
+            tenant_id,
+            shard_number: ShardNumber(3),
+            shard_count: ShardCount(8),
+        };
+        let index = sharded.to_index();
+        assert_eq!(index, ShardIndex::new(ShardNumber(3), ShardCount(8)));
+        assert_eq!(index.get_suffix(), format!("-{}", sharded.shard_slug()));
+        assert_eq!(
+            format!("{tenant_id}{}", index.get_suffix()),
+            sharded.to_string()
+        );
+
+        // Legacy ids keep their zero/zero index, which has no suffix
+        let legacy = TenantShardId::unsharded(tenant_id);
+        assert!(legacy.to_index().is_unsharded());
+        assert_eq!(legacy.to_index().get_suffix(), "");
+        assert_eq!(ShardIndex::from(&legacy), legacy.to_index());
+    }
+
+    #[test]
+    fn even_shards_per_node() {
+        assert_eq!(shards_per_node(ShardCount(8), 3), vec![3, 3, 2]);
+        assert_eq!(shards_per_node(ShardCount(8), 4), vec![2, 2, 2, 2]);
+        assert_eq!(shards_per_node(ShardCount(2), 3), vec![1, 1, 0]);
+        assert_eq!(shards_per_node(ShardCount(0), 2), vec![1, 0]);
+        assert_eq!(shards_per_node(ShardCount(8), 0), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn wide_shard_index_string() -> Result<(), ShardIndexParseError> {
+        let v1 = WideShardIndex::new(7, 10).unwrap();
+        assert_eq!(v1.layout(), LAYOUT_V1);
+        assert_eq!(v1.to_string(), "070a");
+        assert_eq!(v1.get_suffix(), "-070a");
+        assert_eq!(WideShardIndex::from_str("070a")?, v1);
+        // V1 strings are the same as ShardIndex's
+        let index = ShardIndex::from_str("070a").unwrap();
+        assert_eq!(WideShardIndex::try_from(index), Ok(v1));
+
+        let v2 = WideShardIndex::new_v2(7, 17).unwrap();
+        assert_eq!(v2.get_suffix(), "-00070011");
+        assert_eq!(WideShardIndex::from_str("00070011")?, v2);
+
+        let big = WideShardIndex::new(299, 300).unwrap();
+        assert_eq!(big.layout(), LAYOUT_V2);
+        assert_eq!(big.to_string(), "012b012c");
+        assert_eq!(WideShardIndex::from_str(&big.to_string())?, big);
+        assert_eq!(
+            ShardIndex::try_from(big),
+            Err(ShardConfigError::InvalidCount)
+        );
+        assert_eq!(
+            ShardIndex::try_from(v2),
+            Ok(ShardIndex::new(ShardNumber(7), ShardCount(17)))
+        );
+
+        assert_eq!(WideShardIndex::new(0, 0).unwrap().get_suffix(), "");
+        assert_eq!(
+            WideShardIndex::from_str("070a0"),
+            Err(ShardIndexParseError::BadIndex(
+                hex::FromHexError::InvalidStringLength
+            ))
+        );
+
+        // The number must be below the count
+        assert_eq!(
+            WideShardIndex::new(300, 300),
+            Err(ShardConfigError::InvalidNumber)
+        );
+        assert_eq!(
+            WideShardIndex::from_str("012c012c"),
+            Err(ShardIndexParseError::InvalidShard(
+                ShardConfigError::InvalidNumber
+            ))
+        );
+        assert_eq!(
+            WideShardIndex::from_str("0a0a"),
+            Err(ShardIndexParseError::InvalidShard(
+                ShardConfigError::InvalidNumber
+            ))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn wide_shard_index_bytes() -> Result<(), ShardIndexParseError> {
+        let v1 = WideShardIndex::new(7, 10).unwrap();
+        assert_eq!(v1.to_bytes(), vec![7, 10]);
+        assert_eq!(WideShardIndex::from_bytes(&v1.to_bytes())?, v1);
+
+        let big = WideShardIndex::new(299, 300).unwrap();
+        assert_eq!(big.to_bytes(), vec![0x01, 0x2b, 0x01, 0x2c]);
+        assert_eq!(WideShardIndex::from_bytes(&big.to_bytes())?, big);
+
+        assert!(WideShardIndex::from_bytes(&[1, 2, 3]).is_err());
+        assert!(WideShardIndex::from_bytes(&[0x01, 0x2c, 0x01, 0x2c]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn shard_suffix_v2_parse() {
+        // ShardIndex and TenantShardId accept V2 suffixes whose values fit in a byte
+        assert_eq!(
+            ShardIndex::from_str("00070011"),
+            Ok(ShardIndex::new(ShardNumber(7), ShardCount(17)))
+        );
+        assert_eq!(
+            ShardIndex::from_str("012b012c"),
+            Err(ShardIndexParseError::TooWide {
+                number: 299,
+                count: 300
+            })
+        );
+
+        let v1 = TenantShardId::from_str(&format!("{EXAMPLE_TENANT_ID}-0711")).unwrap();
+        let v2 = TenantShardId::from_str(&format!("{EXAMPLE_TENANT_ID}-00070011")).unwrap();
+        assert_eq!(v1, v2);
+        // ...and write them back in V1
+        assert_eq!(v2.to_string(), format!("{EXAMPLE_TENANT_ID}-0711"));
+
+        // A count of 300 round-trips through the V2 suffix, but only as a WideShardIndex
+        let big = WideShardIndex::new(299, 300).unwrap();
+        let s = format!("{EXAMPLE_TENANT_ID}{}", big.get_suffix());
+        assert_eq!(s.len(), 41);
+        assert_eq!(WideShardIndex::from_str(&s[33..]), Ok(big));
+        assert_eq!(
+            TenantShardId::from_str(&s),
+            Err(TenantShardIdParseError::TooWide {
+                number: 299,
+                count: 300
+            })
+        );
+        assert_eq!(
+            TenantShardId::from_str(&format!("{EXAMPLE_TENANT_ID}-000700")),
+            Err(TenantShardIdParseError::BadLength { got: 39 })
+        );
+    }
+
+    #[test]
+    fn wide_shard_index_serde() {
+        for index in [
+            WideShardIndex::new(7, 10).unwrap(),
+            WideShardIndex::new(299, 300).unwrap(),
+        ] {
+            let encoded = serde_json::to_string(&index).unwrap();
+            assert_eq!(encoded, format!("\"{index}\""));
+            assert_eq!(
+                serde_json::from_str::<WideShardIndex>(&encoded).unwrap(),
+                index
+            );
+
+            let encoded = bincode::serialize(&index).unwrap();
+            assert_eq!(
+                bincode::deserialize::<WideShardIndex>(&encoded).unwrap(),
+                index
+            );
+        }
+        assert!(serde_json::from_str::<WideShardIndex>("\"012c012c\"").is_err());
+    }
+
+    #[test]
+    fn shard_identity_split() -> Result<(), ShardSplitError> {
+        let stripe_size = ShardStripeSize(1000);
+        let parent = ShardIdentity::new(ShardNumber(1), ShardCount(2), stripe_size).unwrap();
+        let children = parent.split(ShardCount(8))?;
+        assert_eq!(
+            children.iter().map(|c| c.number).collect::<Vec<_>>(),
+            vec![
+                ShardNumber(1),
+                ShardNumber(3),
+                ShardNumber(5),
+                ShardNumber(7)
+            ]
+        );
+        for child in &children {
+            assert_eq!(child.count, ShardCount(8));
+            assert_eq!(child.stripe_size, parent.stripe_size);
+            assert_eq!(child.layout, parent.layout);
+        }
+
+        for blkno in (0..256).map(|i| i * stripe_size.0) {
+            let key = rel_block_key(1000, blkno);
+            let owners = children.iter().filter(|c| c.is_key_local(&key)).count();
+            assert_eq!(owners, usize::from(parent.is_key_local(&key)));
+        }
+
+        // Splitting to the same count is a no-op, so is refused
+        assert_eq!(
+            parent.split(parent.count),
+            Err(ShardSplitError::InvalidCount {
+                count: ShardCount(2)
+            })
+        );
+        assert_eq!(
+            ShardIdentity::unsharded().split(ShardCount(1)),
+            Err(ShardSplitError::InvalidCount {
+                count: ShardCount(1)
+            })
+        );
+        assert_eq!(
+            parent.split(ShardCount(1)),
+            Err(ShardSplitError::NotLarger {
+                old: ShardCount(2),
+                new: ShardCount(1)
+            })
+        );
+        assert_eq!(
+            parent.split(ShardCount(5)),
+            Err(ShardSplitError::NotMultiple {
+                old: ShardCount(2),
+                new: ShardCount(5)
+            })
+        );
+
+        // Legacy unsharded tenants split like a single shard
+        assert_eq!(ShardIdentity::unsharded().split(ShardCount(4))?.len(), 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn shard_count_power_of_two() {
+        for count in [1, 2, 4, 128] {
+            assert!(ShardCount(count).is_power_of_two());
+        }
+        for count in [0, 3, 6, 255] {
+            assert!(!ShardCount(count).is_power_of_two());
+        }
+    }
 
-#[cfg(test)]
-mod tests {
-    use std::str::FromStr;
+    #[test]
+    fn shard_number_power_of_two_mask() -> Result<(), ShardConfigError> {
+        let stripe_size = ShardStripeSize(1000);
+        for count in [ShardCount(8), ShardCount(6)] {
+            let identity = ShardIdentity::new(ShardNumber(0), count, stripe_size)?;
+            for relnode in 1000..1016 {
+                for blkno in (0..64).map(|i| i * 997) {
+                    let key = rel_block_key(relnode, blkno);
+                    let hash = hash_combine(
+                        murmurhash32(key.field4),
+                        murmurhash32(key.field6 / stripe_size.0),
+                    );
+                    assert_eq!(
+                        identity.get_shard_number(&key),
+                        ShardNumber((hash % count.0 as u32) as u8)
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
 
-    use bincode;
-    use utils::{id::TenantId, Hex};
+    #[test]
+    fn tenant_shard_id_from_str_strict() -> Result<(), TenantShardIdParseError> {
+        assert_eq!(
+            TenantShardId::from_str_strict(EXAMPLE_TENANT_ID),
+            Err(TenantShardIdParseError::MissingSuffix)
+        );
 
-    use super::*;
+        let single = TenantShardId::from_str_strict(&format!("{EXAMPLE_TENANT_ID}-0001"))?;
+        assert_eq!(single.shard_count, ShardCount(1));
+        assert_ne!(single, TenantShardId::from_str(EXAMPLE_TENANT_ID)?);
 
-    const EXAMPLE_TENANT_ID: &str = "1f359dd625e519a1a4e8d7509690f6fc";
+        assert_eq!(
+            TenantShardId::from_str_strict(&format!("{EXAMPLE_TENANT_ID}_0001")),
+            Err(TenantShardIdParseError::MissingSeparator)
+        );
+
+        Ok(())
+    }
 
     #[test]
-    fn tenant_shard_id_string() -> Result<(), hex::FromHexError> {
-        let example = TenantShardId {
-            tenant_id: TenantId::from_str(EXAMPLE_TENANT_ID).unwrap(),
-            shard_count: ShardCount(10),
-            shard_number: ShardNumber(7),
-        };
+    fn synthetic_key_balance() {
+        use super::test_util::{balance_report, synthetic_keys};
 
-        let encoded = format!("{example}");
+        let keys = synthetic_keys(4000, 42);
+        assert_eq!(keys, synthetic_keys(4000, 42));
+        assert_ne!(keys, synthetic_keys(4000, 43));
+        assert!(keys.iter().all(|k| k.is_rel_block_key()));
 
-        let expected = format!("{EXAMPLE_TENANT_ID}-070a");
-        assert_eq!(&encoded, &expected);
+        let report = balance_report(ShardCount(4), DEFAULT_STRIPE_SIZE, &keys);
+        assert_eq!(report.iter().sum::<usize>(), keys.len());
+        for shard_keys in report {
+            assert!((800..1200).contains(&shard_keys), "{shard_keys}");
+        }
 
-        let decoded = TenantShardId::from_str(&encoded)?;
+        assert_eq!(
+            balance_report(ShardCount(0), DEFAULT_STRIPE_SIZE, &keys),
+            vec![keys.len()]
+        );
+    }
 
-        assert_eq!(example, decoded);
+    #[test]
+    fn tenant_shard_id_case_insensitive() -> Result<(), hex::FromHexError> {
+        let lower = format!("{EXAMPLE_TENANT_ID}-070a");
+        let upper = format!("{EXAMPLE_TENANT_ID}-070A");
+        let parsed = TenantShardId::from_str(&upper)?;
+        assert_eq!(parsed, TenantShardId::from_str(&lower)?);
+        assert_eq!(parsed.shard_slug(), "070a");
+        assert_eq!(parsed.to_string(), lower);
 
         Ok(())
     }
 
     #[test]
-    fn tenant_shard_id_binary() -> Result<(), hex::FromHexError> {
-        let example = TenantShardId {
-            tenant_id: TenantId::from_str(EXAMPLE_TENANT_ID).unwrap(),
-            shard_count: ShardCount(10),
-            shard_number: ShardNumber(7),
+    fn tenant_shard_id_legacy_compat() {
+        let tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+        assert!(TenantShardId::verify_legacy_compat(tenant_id));
+        assert!(TenantShardId::verify_legacy_compat(TenantId::from([0; 16])));
+    }
+
+    #[test]
+    fn tenant_shard_ref_conversion() {
+        let tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+        let legacy = TenantShardId::unsharded(tenant_id);
+        let sharded = TenantShardId {
+            tenant_id,
+            shard_number: ShardNumber(1),
+            shard_count: ShardCount(4),
         };
 
-        let encoded = bincode::serialize(&example).unwrap();
-        let expected: [u8; 18] = [
-            0x1f, 0x35, 0x9d, 0xd6, 0x25, 0xe5, 0x19, 0xa1, 0xa4, 0xe8, 0xd7, 0x50, 0x96, 0x90,
-            0xf6, 0xfc, 0x07, 0x0a,
-        ];
-        assert_eq!(Hex(&encoded), Hex(&expected));
+        assert_eq!(
+            TenantShardRef::from(legacy),
+            TenantShardRef::Legacy(tenant_id)
+        );
+        assert_eq!(
+            TenantShardRef::from(sharded),
+            TenantShardRef::Sharded(sharded)
+        );
+        for id in [legacy, sharded] {
+            assert_eq!(TenantShardId::from(TenantShardRef::from(id)), id);
+        }
+    }
 
-        let decoded = bincode::deserialize(&encoded).unwrap();
+    #[test]
+    fn tenant_shard_ref_serde() {
+        let tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+        let legacy = TenantShardRef::Legacy(tenant_id);
+        let sharded = TenantShardRef::Sharded(TenantShardId {
+            tenant_id,
+            shard_number: ShardNumber(1),
+            shard_count: ShardCount(4),
+        });
 
-        assert_eq!(example, decoded);
+        let encoded = serde_json::to_string(&legacy).unwrap();
+        assert_eq!(
+            encoded,
+            format!("{{\"mode\":\"legacy\",\"id\":\"{EXAMPLE_TENANT_ID}\"}}")
+        );
+        assert_eq!(
+            serde_json::from_str::<TenantShardRef>(&encoded).unwrap(),
+            legacy
+        );
 
-        Ok(())
+        let encoded = serde_json::to_string(&sharded).unwrap();
+        assert_eq!(
+            encoded,
+            format!("{{\"mode\":\"sharded\",\"id\":\"{EXAMPLE_TENANT_ID}-0104\"}}")
+        );
+        assert_eq!(
+            serde_json::from_str::<TenantShardRef>(&encoded).unwrap(),
+            sharded
+        );
     }
 
     #[test]
-    fn tenant_shard_id_backward_compat() -> Result<(), hex::FromHexError> {
-        // Test that TenantShardId can decode a TenantId in human
-        // readable form
-        let example = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
-        let encoded = format!("{example}");
-
-        assert_eq!(&encoded, EXAMPLE_TENANT_ID);
+    fn shard_for_relation_end() -> Result<(), ShardConfigError> {
+        let identity = ShardIdentity::new(ShardNumber(0), ShardCount(4), ShardStripeSize(1000))?;
+        let rel = RelTag {
+            forknum: 0,
+            spcnode: 1663,
+            dbnode: 5,
+            relnode: 1000,
+        };
 
-        let decoded = TenantShardId::from_str(&encoded)?;
+        for nblocks in [1, 999, 1000, 1001, 64_000] {
+            assert_eq!(
+                identity.shard_for_rel_end(rel, nblocks),
+                identity.get_shard_number(&rel_block_key(1000, nblocks - 1))
+            );
+        }
+        assert_eq!(identity.shard_for_rel_end(rel, 0), ShardNumber(0));
 
-        assert_eq!(example, decoded.tenant_id);
-        assert_eq!(decoded.shard_count, ShardCount(0));
-        assert_eq!(decoded.shard_number, ShardNumber(0));
+        Ok(())
+    }
 
+    #[test]
+    fn shard_slug_writer() -> std::fmt::Result {
+        let tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+        let mut buf = String::new();
+        for (number, count) in [(0, 0), (1, 4), (13, 17), (254, 255)] {
+            let id = TenantShardId {
+                tenant_id,
+                shard_number: ShardNumber(number),
+                shard_count: ShardCount(count),
+            };
+            buf.clear();
+            id.write_shard_slug(&mut buf)?;
+            assert_eq!(buf, id.shard_slug());
+            assert_eq!(
+                id.to_index().get_suffix(),
+                if count == 0 {
+                    String::new()
+                } else {
+                    format!("-{buf}")
+                }
+            );
+        }
         Ok(())
     }
 
     #[test]
-    fn tenant_shard_id_forward_compat() -> Result<(), hex::FromHexError> {
-        // Test that a legacy TenantShardId encodes into a form that
-        // can be decoded as TenantId
-        let example_tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
-        let example = TenantShardId::unsharded(example_tenant_id);
-        let encoded = format!("{example}");
+    fn shard_count_split_of() {
+        assert!(ShardCount(0).is_unsharded());
+        assert!(!ShardCount(1).is_unsharded());
 
-        assert_eq!(&encoded, EXAMPLE_TENANT_ID);
+        assert!(ShardCount(4).is_split_of(ShardCount(1)));
+        assert!(ShardCount(8).is_split_of(ShardCount(4)));
+        assert!(!ShardCount(8).is_split_of(ShardCount(3)));
+        assert!(!ShardCount(4).is_split_of(ShardCount(4)));
+        assert!(!ShardCount(4).is_split_of(ShardCount(8)));
 
-        let decoded = TenantId::from_str(&encoded)?;
+        // Legacy counts are not part of the rule: callers treat them as a count of 1
+        assert!(!ShardCount(4).is_split_of(ShardCount(0)));
+        assert!(!ShardCount(0).is_split_of(ShardCount(4)));
+    }
 
-        assert_eq!(example_tenant_id, decoded);
+    #[test]
+    fn tenant_shard_id_ring_position() {
+        let tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+        let shards = TenantShardId::select(tenant_id, ShardCount(8), ShardSelector::All);
+
+        for ring_size in [1, 1000, u64::MAX] {
+            let ring_size = NonZeroU64::new(ring_size).unwrap();
+            for shard in &shards {
+                let position = shard.ring_position(ring_size);
+                assert!(position < ring_size.get());
+                assert_eq!(position, shard.ring_position(ring_size));
+            }
+        }
+
+        let positions: BTreeSet<_> = shards
+            .iter()
+            .map(|s| s.ring_position(NonZeroU64::MAX))
+            .collect();
+        assert_eq!(positions.len(), shards.len());
+    }
+
+    #[test]
+    fn sample_owned_keys_distinct_stripes() -> Result<(), ShardConfigError> {
+        let stripe_size = ShardStripeSize(1000);
+        let identity = ShardIdentity::new(ShardNumber(2), ShardCount(4), stripe_size)?;
+        let range = rel_block_key(1000, 500)..rel_block_key(1000, 100_000);
+
+        let samples = identity.sample_owned_keys(range.clone(), 10);
+        assert_eq!(samples.len(), 10);
+        let stripes: BTreeSet<_> = samples.iter().map(|k| k.field6 / stripe_size.0).collect();
+        assert_eq!(stripes.len(), samples.len());
+        for key in &samples {
+            assert!(range.contains(key));
+            assert!(identity.is_key_local(key));
+        }
+
+        // Limited by the number of owned stripes when the range is small
+        let all = identity.sample_owned_keys(range.clone(), usize::MAX);
+        assert!(all.len() < 100);
+        assert_eq!(&all[..10], &samples[..]);
+        assert!(identity.sample_owned_keys(range, 0).is_empty());
 
         Ok(())
     }
 
     #[test]
-    fn tenant_shard_id_legacy_binary() -> Result<(), hex::FromHexError> {
-        // Unlike in human readable encoding, binary encoding does not
-        // do any special handling of legacy unsharded TenantIds: this test
-        // is equivalent to the main test for binary encoding, just verifying
-        // that the same behavior applies when we have used `unsharded()` to
-        // construct a TenantShardId.
-        let example = TenantShardId::unsharded(TenantId::from_str(EXAMPLE_TENANT_ID).unwrap());
-        let encoded = bincode::serialize(&example).unwrap();
+    fn tenant_shard_id_eq_tenant_id() {
+        let tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+        let other = TenantId::from([0; 16]);
+        let legacy = TenantShardId::unsharded(tenant_id);
+        let sharded = TenantShardId {
+            tenant_id,
+            shard_number: ShardNumber(3),
+            shard_count: ShardCount(4),
+        };
 
-        let expected: [u8; 18] = [
-            0x1f, 0x35, 0x9d, 0xd6, 0x25, 0xe5, 0x19, 0xa1, 0xa4, 0xe8, 0xd7, 0x50, 0x96, 0x90,
-            0xf6, 0xfc, 0x00, 0x00,
-        ];
-        assert_eq!(Hex(&encoded), Hex(&expected));
+        for id in [legacy, sharded] {
+            assert!(id == tenant_id);
+            assert!(tenant_id == id);
+            assert!(id != other);
+            assert!(other != id);
+        }
+    }
 
-        let decoded = bincode::deserialize::<TenantShardId>(&encoded).unwrap();
-        assert_eq!(example, decoded);
+    #[test]
+    fn tenant_shard_id_from_str_validated() -> Result<(), TenantShardIdParseError> {
+        let id = TenantShardId::from_str_validated(&format!("{EXAMPLE_TENANT_ID}-0102"))?;
+        assert_eq!(id.shard_number, ShardNumber(1));
+
+        assert_eq!(
+            TenantShardId::from_str_validated(&format!("{EXAMPLE_TENANT_ID}-0202")),
+            Err(TenantShardIdParseError::InvalidShard {
+                number: ShardNumber(2),
+                count: ShardCount(2)
+            })
+        );
+        assert!(TenantShardId::from_str_validated(&format!("{EXAMPLE_TENANT_ID}-0402")).is_err());
+        assert!(TenantShardId::from_str_validated(&format!("{EXAMPLE_TENANT_ID}-0100")).is_err());
+        // The lenient parser still accepts these
+        assert!(TenantShardId::from_str(&format!("{EXAMPLE_TENANT_ID}-0202")).is_ok());
+
+        let legacy = TenantShardId::from_str_validated(&format!("{EXAMPLE_TENANT_ID}-0000"))?;
+        assert_eq!(legacy.shard_count, ShardCount(0));
+        assert_eq!(
+            TenantShardId::from_str_validated(EXAMPLE_TENANT_ID)?,
+            legacy
+        );
 
         Ok(())
     }
 
     #[test]
-    fn shard_identity_validation() -> Result<(), ShardConfigError> {
-        // Happy cases
-        ShardIdentity::new(ShardNumber(0), ShardCount(1), DEFAULT_STRIPE_SIZE)?;
-        ShardIdentity::new(ShardNumber(0), ShardCount(1), ShardStripeSize(1))?;
-        ShardIdentity::new(ShardNumber(254), ShardCount(255), ShardStripeSize(1))?;
+    fn tenant_shard_id_short_code() {
+        let tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+        let id = TenantShardId {
+            tenant_id,
+            shard_number: ShardNumber(7),
+            shard_count: ShardCount(10),
+        };
+        assert_eq!(id.short_code(), "90f6fc-070a");
+        assert_eq!(TenantShardId::unsharded(tenant_id).short_code(), "90f6fc");
 
+        let mut candidates = TenantShardId::select(tenant_id, ShardCount(10), ShardSelector::All);
+        candidates.push(TenantShardId::unsharded(TenantId::from([0; 16])));
         assert_eq!(
-            ShardIdentity::new(ShardNumber(0), ShardCount(0), DEFAULT_STRIPE_SIZE),
-            Err(ShardConfigError::InvalidCount)
+            TenantShardId::resolve_short_code("90f6fc-070a", &candidates),
+            Some(id)
         );
         assert_eq!(
-            ShardIdentity::new(ShardNumber(10), ShardCount(10), DEFAULT_STRIPE_SIZE),
-            Err(ShardConfigError::InvalidNumber)
+            TenantShardId::resolve_short_code("90F6FC-070A", &candidates),
+            Some(id)
         );
         assert_eq!(
-            ShardIdentity::new(ShardNumber(11), ShardCount(10), DEFAULT_STRIPE_SIZE),
-            Err(ShardConfigError::InvalidNumber)
+            TenantShardId::resolve_short_code("000000", &candidates),
+            Some(TenantShardId::unsharded(TenantId::from([0; 16])))
         );
         assert_eq!(
-            ShardIdentity::new(ShardNumber(255), ShardCount(255), DEFAULT_STRIPE_SIZE),
-            Err(ShardConfigError::InvalidNumber)
+            TenantShardId::resolve_short_code("90f6fc-0b0a", &candidates),
+            None
         );
+
+        // Ambiguous codes resolve to nothing
+        candidates.push(id);
         assert_eq!(
-            ShardIdentity::new(ShardNumber(0), ShardCount(1), ShardStripeSize(0)),
-            Err(ShardConfigError::InvalidStripeSize)
+            TenantShardId::resolve_short_code("90f6fc-070a", &candidates),
+            None
+        );
+    }
+
+    #[test]
+    fn shard_identity_slug() -> Result<(), ShardConfigError> {
+        let identity = ShardIdentity::new(ShardNumber(7), ShardCount(10), DEFAULT_STRIPE_SIZE)?;
+        assert_eq!(identity.shard_slug(), "070a");
+        assert_eq!(
+            identity.index(),
+            ShardIndex::new(ShardNumber(7), ShardCount(10))
         );
+        assert_eq!(identity.index().to_string(), identity.shard_slug());
+
+        assert_eq!(ShardIdentity::unsharded().shard_slug(), "0000");
+        assert!(ShardIdentity::unsharded().index().is_unsharded());
 
         Ok(())
     }
 
     #[test]
-    fn shard_index_human_encoding() -> Result<(), hex::FromHexError> {
-        let example = ShardIndex {
-            shard_number: ShardNumber(13),
-            shard_count: ShardCount(17),
-        };
-        let expected: String = "0d11".to_string();
-        let encoded = format!("{example}");
-        assert_eq!(&encoded, &expected);
+    fn range_single_shard() -> Result<(), ShardConfigError> {
+        let identity = ShardIdentity::new(ShardNumber(0), ShardCount(4), ShardStripeSize(1000))?;
+
+        let narrow = rel_block_key(1000, 2100)..rel_block_key(1000, 2900);
+        assert_eq!(
+            identity.range_is_single_shard(narrow),
+            Some(identity.get_shard_number(&rel_block_key(1000, 2100)))
+        );
+
+        let wide = rel_block_key(1000, 0)..rel_block_key(1000, 100_000);
+        assert_eq!(identity.range_is_single_shard(wide.clone()), None);
+
+        assert_eq!(
+            ShardIdentity::unsharded().range_is_single_shard(wide),
+            Some(ShardNumber(0))
+        );
 
-        let decoded = ShardIndex::from_str(&encoded)?;
-        assert_eq!(example, decoded);
         Ok(())
     }
 
     #[test]
-    fn shard_index_binary_encoding() -> Result<(), hex::FromHexError> {
-        let example = ShardIndex {
-            shard_number: ShardNumber(13),
-            shard_count: ShardCount(17),
+    fn tenant_shard_id_from_generational_str() -> Result<(), TenantShardIdParseError> {
+        let tenant_id = TenantId::from_str(EXAMPLE_TENANT_ID).unwrap();
+        let sharded = TenantShardId {
+            tenant_id,
+            shard_number: ShardNumber(1),
+            shard_count: ShardCount(4),
         };
-        let expected: [u8; 2] = [0x0d, 0x11];
 
-        let encoded = bincode::serialize(&example).unwrap();
-        assert_eq!(Hex(&encoded), Hex(&expected));
-        let decoded = bincode::deserialize(&encoded).unwrap();
-        assert_eq!(example, decoded);
+        assert_eq!(
+            TenantShardId::from_generational_str(&format!("{EXAMPLE_TENANT_ID}-0104-00000005"))?,
+            (sharded, 5)
+        );
+        assert_eq!(
+            TenantShardId::from_generational_str(&format!("{EXAMPLE_TENANT_ID}-0000001a"))?,
+            (TenantShardId::unsharded(tenant_id), 0x1a)
+        );
+
+        assert_eq!(
+            TenantShardId::from_generational_str(EXAMPLE_TENANT_ID),
+            Err(TenantShardIdParseError::MissingSeparator)
+        );
+        assert_eq!(
+            TenantShardId::from_generational_str(&format!("{EXAMPLE_TENANT_ID}-0104")),
+            Err(TenantShardIdParseError::BadGeneration)
+        );
+        assert_eq!(
+            TenantShardId::from_generational_str(&format!("{EXAMPLE_TENANT_ID}-0104-0000000g")),
+            Err(TenantShardIdParseError::BadGeneration)
+        );
+        assert_eq!(
+            TenantShardId::from_generational_str(&format!("{EXAMPLE_TENANT_ID}-0104-+0000005")),
+            Err(TenantShardIdParseError::BadGeneration)
+        );
+        assert!(matches!(
+            TenantShardId::from_generational_str(&format!("{EXAMPLE_TENANT_ID}-01zz-00000005")),
+            Err(TenantShardIdParseError::BadShardSuffix(_))
+        ));
 
         Ok(())
     }
+
+    #[test]
+    fn tenant_shard_id_deserialize_struct_form() {
+        let expected = TenantShardId {
+            tenant_id: TenantId::from_str(EXAMPLE_TENANT_ID).unwrap(),
+            shard_number: ShardNumber(7),
+            shard_count: ShardCount(10),
+        };
+
+        let compact = format!("\"{EXAMPLE_TENANT_ID}-070a\"");
+        assert_eq!(
+            serde_json::from_str::<TenantShardId>(&compact).unwrap(),
+            expected
+        );
+
+        assert_eq!(
+            serde_json::from_str::<TenantShardIdCompat>(&compact)
+                .unwrap()
+                .0,
+            expected
+        );
+
+        // Only the compat wrapper accepts the fields spelled out
+        let fields = format!(
+            "{{\"tenant_id\": \"{EXAMPLE_TENANT_ID}\", \"shard_number\": 7, \"shard_count\": 10}}"
+        );
+        assert!(serde_json::from_str::<TenantShardId>(&fields).is_err());
+        assert_eq!(
+            serde_json::from_str::<TenantShardIdCompat>(&fields)
+                .unwrap()
+                .0,
+            expected
+        );
+
+        // Serialization still uses the compact form
+        assert_eq!(
+            serde_json::to_string(&TenantShardIdCompat(expected)).unwrap(),
+            compact
+        );
+
+        let missing = format!("{{\"tenant_id\": \"{EXAMPLE_TENANT_ID}\", \"shard_number\": 7}}");
+        assert!(serde_json::from_str::<TenantShardIdCompat>(&missing).is_err());
+
+        #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+        struct Holder {
+            #[serde(with = "tenant_shard_id_compat")]
+            id: TenantShardId,
+        }
+        let holder = serde_json::from_str::<Holder>(&format!("{{\"id\": {fields}}}")).unwrap();
+        assert_eq!(holder.id, expected);
+        assert_eq!(
+            serde_json::to_string(&holder).unwrap(),
+            format!("{{\"id\":{compact}}}")
+        );
+    }
+
+    #[test]
+    fn shard_index_const() {
+        const IDX: ShardIndex = ShardIndex::new(ShardNumber(1), ShardCount(4));
+        const UNSHARDED: ShardIndex = ShardIndex::unsharded();
+        const UNSHARDED_FLAGS: [bool; 2] = [IDX.is_unsharded(), UNSHARDED.is_unsharded()];
+
+        assert_eq!(IDX.shard_number, ShardNumber(1));
+        assert_eq!(IDX.shard_count, ShardCount(4));
+        assert_eq!(UNSHARDED_FLAGS, [false, true]);
+    }
+
+    #[test]
+    fn split_layers_moved_estimate() {
+        assert_eq!(
+            layers_moved_estimate(1000, ShardCount(2), ShardCount(4)),
+            Ok(500)
+        );
+        assert_eq!(
+            layers_moved_estimate(1000, ShardCount(1), ShardCount(8)),
+            Ok(875)
+        );
+        assert_eq!(
+            layers_moved_estimate(1000, ShardCount(0), ShardCount(2)),
+            Ok(500)
+        );
+        assert_eq!(
+            layers_moved_estimate(1000, ShardCount(2), ShardCount(3)),
+            Err(ShardConfigError::InvalidCount)
+        );
+        assert_eq!(
+            layers_moved_estimate(1000, ShardCount(4), ShardCount(4)),
+            Err(ShardConfigError::InvalidCount)
+        );
+
+        // Layer counts too large to multiply by the shard count in a usize
+        assert_eq!(
+            layers_moved_estimate(usize::MAX, ShardCount(1), ShardCount(2)),
+            Ok(usize::MAX / 2)
+        );
+        assert_eq!(
+            layers_moved_estimate(usize::MAX, ShardCount(1), ShardCount(255)),
+            Ok((usize::MAX as u128 * 254 / 255) as usize)
+        );
+    }
 }
@@ -630,7 +630,7 @@ impl PageServerConf {
     }
 
     pub fn tenant_path(&self, tenant_shard_id: &TenantShardId) -> Utf8PathBuf {
-        self.tenants_path().join(tenant_shard_id.to_string())
+        self.tenants_path().join(tenant_shard_id.local_dir_name())
     }
 
     pub fn tenant_ignore_mark_file_path(&self, tenant_shard_id: &TenantShardId) -> Utf8PathBuf {
@@ -377,17 +377,14 @@ fn load_tenant_config(
         return Ok(None);
     }
 
-    let tenant_shard_id = match tenant_dir_path
-        .file_name()
-        .unwrap_or_default()
-        .parse::<TenantShardId>()
-    {
-        Ok(id) => id,
-        Err(_) => {
-            warn!("Invalid tenant path (garbage in our repo directory?): {tenant_dir_path}",);
-            return Ok(None);
-        }
-    };
+    let tenant_shard_id =
+        match TenantShardId::from_local_dir_name(tenant_dir_path.file_name().unwrap_or_default()) {
+            Ok(id) => id,
+            Err(_) => {
+                warn!("Invalid tenant path (garbage in our repo directory?): {tenant_dir_path}",);
+                return Ok(None);
+            }
+        };
 
     Ok(Some((
         tenant_shard_id,
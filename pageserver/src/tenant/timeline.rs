@@ -4390,10 +4390,7 @@ impl Timeline {
     }
 
     pub(crate) fn get_shard_index(&self) -> ShardIndex {
-        ShardIndex {
-            shard_number: self.tenant_shard_id.shard_number,
-            shard_count: self.tenant_shard_id.shard_count,
-        }
+        self.tenant_shard_id.to_index()
     }
 }
 